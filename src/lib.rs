@@ -1,54 +1,178 @@
-mod simulation;
-use simulation::Simulation;
+pub mod simulation;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use rayon::prelude::*;
+use serde::Serialize;
+use simulation::{BenchResult, Simulation, SimulationError};
+use simulation::rules::SimulationRules;
+use simulation::stats::GameOutcome;
 
 const DEFAULT_TICKS: usize = 100000;
+// A single game reports its progress once every this many ticks
+const PROGRESS_TICKS: usize = 1000;
+
+// Called with the amount of work done so far and the total amount of work,
+// counted in ticks for a single game and in games otherwise
+pub type Progress<'a> = dyn Fn(usize, usize) + Sync + 'a;
+
+// Outcome of a single game
+#[derive(Debug, Copy, Clone, Serialize)]
+pub struct GameResult {
+    #[serde(flatten)]
+    pub outcome: GameOutcome,
+    pub ticks: usize
+}
+impl Display for GameResult {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let ((red_points, black_points), (red_ants, black_ants)) = (self.outcome.points(), self.outcome.ants());
+        match self.outcome {
+            GameOutcome::RedWins { .. } if red_points == black_points => write!(f, "Red ants won with {} ants left against {} for black ants, both teams got {} points", red_ants, black_ants, red_points),
+            GameOutcome::BlackWins { .. } if red_points == black_points => write!(f, "Black ants won with {} ants left against {} for red ants, both teams got {} points", black_ants, red_ants, black_points),
+            GameOutcome::RedWins { .. } => write!(f, "Red ants won with {} against {} for black ants", red_points, black_points),
+            GameOutcome::BlackWins { .. } => write!(f, "Black ants won with {} against {} for red ants", black_points, red_points),
+            GameOutcome::Draw { .. } => write!(f, "It's a draw! Both teams got {} points", black_points)
+        }
+    }
+}
+
+// Average points of one brain over a series of games
+#[derive(Debug, Clone, Serialize)]
+pub struct BrainAverage {
+    pub brain: String,
+    pub as_red: u32,
+    pub as_black: u32,
+    pub total: u32
+}
+
+// Outcome of a series of games between two brains
+#[derive(Debug, Clone, Serialize)]
+pub struct AverageScore {
+    pub games: Vec<GameResult>,
+    pub brains: [BrainAverage; 2]
+}
+impl Display for AverageScore {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (i, b) in self.brains.iter().enumerate() {
+            if i != 0 {
+                writeln!(f)?
+            }
+            write!(f, "Brain {} averaged:\n- {} points as red\n- {} points as black\n- {} points total", b.brain, b.as_red, b.as_black, b.total)?
+        }
+        Ok(())
+    }
+}
+
+// Results of one brain over a whole tournament
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Standing {
+    pub brain: String,
+    pub wins: u32,
+    pub losses: u32,
+    pub draws: u32,
+    pub points_for: u32,
+    pub points_against: u32
+}
+
+// Standings of a tournament, ranked by wins, then by points difference,
+// ties being broken by brain name so that the ordering is stable
+#[derive(Debug, Clone, Serialize)]
+pub struct Tournament {
+    pub standings: Vec<Standing>
+}
+impl Display for Tournament {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let width = self.standings.iter()
+            .map(|s| s.brain.len())
+            .max()
+            .unwrap_or_default()
+            .max("Brain".len());
+        write!(f, "{:<width$}  {:>5} {:>5} {:>5} {:>8} {:>8}", "Brain", "W", "L", "D", "PF", "PA")?;
+        for s in &self.standings {
+            write!(f, "\n{:<width$}  {:>5} {:>5} {:>5} {:>8} {:>8}", s.brain, s.wins, s.losses, s.draws, s.points_for, s.points_against)?
+        }
+        Ok(())
+    }
+}
+
+// Plays a game until the end given an already loaded simulation, as well as the number
+// of ticks per game (defaulting to DEFAULT_TICKS)
+// The game stops early if it ends in a stalemate
+pub fn play(mut simulation: Simulation, max_ticks: Option<usize>, progress: Option<&Progress>) -> GameResult {
+    let max_ticks = max_ticks.unwrap_or(DEFAULT_TICKS);
+    let mut ticks = 0;
+    while ticks < max_ticks && !simulation.is_finished() {
+        simulation.process_tick();
+        ticks += 1;
+        if let Some(progress) = progress.filter(|_| ticks.is_multiple_of(PROGRESS_TICKS)) {
+            progress(ticks, max_ticks)
+        }
+    }
+    // A game ending in a stalemate is done early
+    if let Some(progress) = progress {
+        progress(max_ticks, max_ticks)
+    }
+
+    GameResult { outcome: simulation.outcome(), ticks }
+}
 
 // Runs one game given a world, brains files, as well as the number of ticks per game
-// (defaulting to DEFAULT_TICKS)
-pub fn run(world: String, brains: (String, String), ticks: Option<usize>) {
-    let mut simulation = Simulation::new(
+// (defaulting to DEFAULT_TICKS), an optional seed and the rules of the game
+pub fn run(world: String, brains: (String, String), ticks: Option<usize>, seed: Option<u64>, rules: SimulationRules, progress: Option<&Progress>) -> Result<GameResult, SimulationError> {
+    let simulation = Simulation::with_rules(
         &world,
         &brains.0,
         &brains.1,
-    );
+        seed,
+        rules
+    )?;
 
-    for _ in 0..ticks.unwrap_or(DEFAULT_TICKS) {
-        simulation.process_tick()
-    }
+    Ok(play(simulation, ticks, progress))
+}
 
-    let (red_points, black_points) = simulation.points();
-    if red_points > black_points {
-        println!("Red ants won with {} against {} for black ants", red_points, black_points)
-    } else if black_points > red_points {
-        println!("Black ants won with {} against {} for red ants", black_points, red_points)
-    } else {
-        println!("It's a draw! Both teams got {} points", black_points)
-    }
+// Times a single game given an already loaded simulation, as well as the number of
+// ticks to run (defaulting to DEFAULT_TICKS)
+// Every tick is run, even after the game has ended
+pub fn bench(mut simulation: Simulation, ticks: Option<usize>) -> BenchResult {
+    simulation.benchmark(ticks.unwrap_or(DEFAULT_TICKS))
 }
 
 // Returns the average score between two brains over a given number of games in a given world
-pub fn get_average_score(world: String, brains: (String, String), games: usize, ticks: Option<usize>) {
+// At least one game must be played
+// When a seed is given, each game is seeded with seed + its index
+// Progress is reported each time a game ends
+pub fn get_average_score(world: String, brains: (String, String), games: usize, ticks: Option<usize>, seed: Option<u64>, rules: SimulationRules, progress: Option<&Progress>) -> Result<AverageScore, SimulationError> {
     // If the number of games is uneven, we'll play one more
-    let games = if games % 2 != 0 {
+    let games = if !games.is_multiple_of(2) {
         games + 1
     } else {
         games
     };
 
+    // Games are independent, so they are played in parallel
+    // Each simulation is built inside its own task, results keeping the games' order
+    let games_done = AtomicUsize::new(0);
+    let results: Vec<GameResult> = (0..games)
+        .into_par_iter()
+        .map(|g| {
+            let simulation = Simulation::with_rules(
+                &world,
+                if g % 2 == 0 { &brains.0 } else { &brains.1 },
+                if g % 2 == 0 { &brains.1 } else { &brains.0 },
+                seed.map(|s| s.wrapping_add(g as u64)),
+                rules
+            )?;
+            let result = play(simulation, ticks, None);
+            if let Some(progress) = progress {
+                progress(games_done.fetch_add(1, Ordering::Relaxed) + 1, games)
+            }
+            Ok(result)
+        })
+        .collect::<Result<Vec<GameResult>, SimulationError>>()?;
+
     let mut total_score_red = (0, 0);
     let mut total_score_black = (0, 0);
-    for g in 0..games {
-        let mut simulation = Simulation::new(
-            &world,
-            if g % 2 == 0 { &brains.0 } else { &brains.1 },
-            if g % 2 == 0 { &brains.1 } else { &brains.0 },
-        );
-
-        for _ in 0..ticks.unwrap_or(DEFAULT_TICKS) {
-            simulation.process_tick()
-        }
-
-        let (red_points, black_points) = simulation.points();
+    for (g, result) in results.iter().enumerate() {
+        let (red_points, black_points) = result.outcome.points();
         if g % 2 == 0 {
             total_score_red.0 += red_points;
             total_score_black.1 += black_points;
@@ -61,6 +185,159 @@ pub fn get_average_score(world: String, brains: (String, String), games: usize,
     let average_red = (total_score_red.0 / (games as u32 / 2), total_score_red.1 / (games as u32 / 2));
     let average_black = (total_score_black.0 / (games as u32 / 2), total_score_black.1 / (games as u32 / 2));
     let average = ((total_score_red.0 + total_score_black.0) / games as u32, (total_score_red.1 + total_score_black.1) / games as u32);
-    println!("Brain {} averaged:\n- {} points as red\n- {} points as black\n- {} points total", brains.0, average_red.0, average_black.0, average.0);
-    println!("Brain {} averaged:\n- {} points as red\n- {} points as black\n- {} points total", brains.1, average_red.1, average_black.1, average.1);
-}
\ No newline at end of file
+    Ok(AverageScore {
+        games: results,
+        brains: [
+            BrainAverage { brain: brains.0, as_red: average_red.0, as_black: average_black.0, total: average.0 },
+            BrainAverage { brain: brains.1, as_red: average_red.1, as_black: average_black.1, total: average.1 }
+        ]
+    })
+}
+
+// Plays every pair of brains against each other, both ways, over a given number of
+// games per pair (rounded up to an even number as in get_average_score)
+// Progress counts the games played over the whole tournament
+pub fn tournament(world: String, brains: Vec<String>, games: usize, ticks: Option<usize>, seed: Option<u64>, rules: SimulationRules, progress: Option<&Progress>) -> Result<Tournament, SimulationError> {
+    let mut standings: Vec<Standing> = brains.iter()
+        .map(|b| Standing { brain: b.clone(), ..Default::default() })
+        .collect();
+
+    let games_per_pair = games + games % 2;
+    let total_games = games_per_pair * brains.len() * brains.len().saturating_sub(1) / 2;
+    let mut pair_index = 0;
+    for i in 0..brains.len() {
+        for j in (i + 1)..brains.len() {
+            let games_before = pair_index * games_per_pair;
+            let pair_progress = progress.map(|progress| {
+                move |done: usize, _: usize| progress(games_before + done, total_games)
+            });
+            let score = get_average_score(
+                world.clone(),
+                (brains[i].clone(), brains[j].clone()),
+                games,
+                ticks,
+                seed.map(|s| s.wrapping_add((pair_index * games_per_pair) as u64)),
+                rules,
+                pair_progress.as_ref().map(|p| p as &Progress)
+            )?;
+            pair_index += 1;
+
+            // Brain i plays red on even games and black on odd ones
+            for (g, result) in score.games.iter().enumerate() {
+                let (red_points, black_points) = result.outcome.points();
+                let i_is_red = g % 2 == 0;
+                let (i_points, j_points) = if i_is_red {
+                    (red_points, black_points)
+                } else {
+                    (black_points, red_points)
+                };
+                standings[i].points_for += i_points;
+                standings[i].points_against += j_points;
+                standings[j].points_for += j_points;
+                standings[j].points_against += i_points;
+                match result.outcome {
+                    GameOutcome::Draw { .. } => {
+                        standings[i].draws += 1;
+                        standings[j].draws += 1;
+                    }
+                    GameOutcome::RedWins { .. } if i_is_red => {
+                        standings[i].wins += 1;
+                        standings[j].losses += 1;
+                    }
+                    GameOutcome::BlackWins { .. } if !i_is_red => {
+                        standings[i].wins += 1;
+                        standings[j].losses += 1;
+                    }
+                    _ => {
+                        standings[j].wins += 1;
+                        standings[i].losses += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    standings.sort_by(|a, b| {
+        b.wins.cmp(&a.wins)
+            .then_with(|| (b.points_for as i64 - b.points_against as i64).cmp(&(a.points_for as i64 - a.points_against as i64)))
+            .then_with(|| a.brain.cmp(&b.brain))
+    });
+    Ok(Tournament { standings })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use crate::simulation::tests::{fixture, BRAIN, FORAGER, IDLE, TINY};
+
+    #[test]
+    fn game_result_as_json() {
+        let simulation = Simulation::from_sources(TINY, FORAGER, IDLE, Some(7), SimulationRules::default()).unwrap();
+        let result = play(simulation, Some(3000), None);
+        let json: serde_json::Value = serde_json::from_str(&serde_json::to_string(&result).unwrap()).unwrap();
+
+        assert_eq!(json, serde_json::json!({
+            "red_points": 11,
+            "black_points": 0,
+            "winner": "red",
+            "red_ants": 4,
+            "black_ants": 4,
+            "ticks": 3000
+        }));
+    }
+
+    #[test]
+    fn progress_is_reported_for_each_game() {
+        let world = fixture("progress", "tiny.world", TINY);
+        let brains = (fixture("progress", "forager.brain", FORAGER), fixture("progress", "idle.brain", IDLE));
+        let calls = Mutex::new(vec!());
+        let record = |done, total| calls.lock().unwrap().push((done, total));
+
+        get_average_score(world.clone(), brains.clone(), 3, Some(500), Some(1), SimulationRules::default(), Some(&record)).unwrap();
+        let mut games = calls.lock().unwrap().split_off(0);
+        games.sort();
+        assert_eq!(games, vec![(1, 4), (2, 4), (3, 4), (4, 4)]);
+
+        // A single game reports every PROGRESS_TICKS ticks and once more when it ends
+        run(world, brains, Some(2500), Some(1), SimulationRules::default(), Some(&record)).unwrap();
+        assert_eq!(*calls.lock().unwrap(), vec![(1000, 2500), (2000, 2500), (2500, 2500)]);
+    }
+
+    #[test]
+    fn tournament_standings_are_stable_and_symmetric() {
+        let world = fixture("tournament", "tiny.world", TINY);
+        let brains = vec![
+            fixture("tournament", "forager.brain", FORAGER),
+            fixture("tournament", "idle.brain", IDLE),
+            fixture("tournament", "wanderer.brain", BRAIN)
+        ];
+        let play = || tournament(world.clone(), brains.clone(), 2, Some(2000), Some(3), SimulationRules::default(), None).unwrap();
+        let standings = play().standings;
+
+        assert_eq!(format!("{:?}", play().standings), format!("{:?}", standings));
+        assert_eq!(standings[0].brain, brains[0]);
+        let total = |f: fn(&Standing) -> u32| standings.iter().map(f).sum::<u32>();
+        assert_eq!(total(|s| s.wins), total(|s| s.losses));
+        assert_eq!(total(|s| s.points_for), total(|s| s.points_against));
+        // Each brain plays both colours against the two others
+        assert!(standings.iter().all(|s| s.wins + s.losses + s.draws == 4));
+    }
+
+    #[test]
+    fn parallel_games_match_sequential_ones() {
+        let world = fixture("parallel_games", "tiny.world", TINY);
+        let brains = (fixture("parallel_games", "forager.brain", FORAGER), fixture("parallel_games", "wanderer.brain", BRAIN));
+        let score = get_average_score(world.clone(), brains.clone(), 6, Some(2000), Some(11), SimulationRules::default(), None).unwrap();
+
+        let sequential: Vec<(GameOutcome, usize)> = (0..6)
+            .map(|g| {
+                let brains = if g % 2 == 0 { brains.clone() } else { (brains.1.clone(), brains.0.clone()) };
+                let result = run(world.clone(), brains, Some(2000), Some(11 + g), SimulationRules::default(), None).unwrap();
+                (result.outcome, result.ticks)
+            })
+            .collect();
+        let parallel: Vec<(GameOutcome, usize)> = score.games.iter().map(|r| (r.outcome, r.ticks)).collect();
+        assert_eq!(parallel, sequential);
+    }
+}
@@ -1,28 +1,227 @@
-use fourmisse_arena::{get_average_score, run};
-use clap::Parser;
+use fourmisse_arena::{bench, get_average_score, play, tournament, Progress};
+use fourmisse_arena::simulation::{Simulation, SimulationError};
+use fourmisse_arena::simulation::rules::SimulationRules;
+use fourmisse_arena::simulation::generator::random_world;
+use fourmisse_arena::simulation::instruction::{check_brain, disassemble, load_instructionset};
+use clap::{Parser, Subcommand, ValueEnum};
+use clap::builder::RangedU64ValueParser;
+use indicatif::{ProgressBar, ProgressDrawTarget};
+use serde::Serialize;
+use std::fmt::Display;
+use std::fs;
+use std::process::exit;
+
+#[derive(Copy, Clone, Default, ValueEnum)]
+enum Format {
+    #[default]
+    Text,
+    Json
+}
 
 #[derive(Parser)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, subcommand_negates_reqs = true)]
 struct Args {
-    #[arg(short, long, value_name = "WORLD_FILE")]
-    world: String,
-    #[arg(short, long, value_name = "RED_BRAIN_FILE")]
-    red_brain: String,
-    #[arg(short, long, value_name = "BLACK_BRAIN_FILE")]
-    black_brain: String,
-
-    #[arg(short, long, value_name = "TICKS_PER_GAME")]
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// World the game is played in
+    #[arg(short, long, value_name = "WORLD_FILE", required_unless_present = "disassemble")]
+    world: Option<String>,
+    /// Brain controlling the red ants (spawned on '+' nests)
+    #[arg(short, long, value_name = "RED_BRAIN_FILE", required_unless_present = "disassemble")]
+    red_brain: Option<String>,
+    /// Brain controlling the black ants (spawned on '-' nests)
+    #[arg(short, long, value_name = "BLACK_BRAIN_FILE", required_unless_present = "disassemble")]
+    black_brain: Option<String>,
+    /// Prints a brain's instructions with their resolved jump targets instead of playing
+    #[arg(long, value_name = "BRAIN_FILE", exclusive = true)]
+    disassemble: Option<String>,
+
+    /// Number of ticks a game lasts
+    #[arg(short, long, value_name = "TICKS_PER_GAME", global = true)]
     ticks: Option<usize>,
-    #[arg(short, long, value_name = "NUMBER_OF_GAMES")]
-    games: Option<usize>
+    /// Plays several games, swapping colours every other game, and prints average scores
+    #[arg(short, long, value_name = "NUMBER_OF_GAMES", conflicts_with = "bench", value_parser = at_least_one())]
+    games: Option<usize>,
+    /// Times a single game running for every tick and prints how many ticks were run per second
+    #[arg(long)]
+    bench: bool,
+    /// Seed making games reproducible
+    #[arg(short, long, value_name = "SEED", global = true)]
+    seed: Option<u64>,
+    /// How results are printed
+    #[arg(short, long, value_enum, default_value_t, global = true)]
+    format: Format,
+    /// Ticks an ant has to wait after moving
+    #[arg(long, value_name = "TICKS", global = true)]
+    move_cooldown: Option<u8>,
+    /// Shows a progress bar on stderr while playing
+    #[arg(long, global = true)]
+    progress: bool
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Plays every pair of .brain files found in a directory against each other
+    Tournament {
+        /// World the games are played in
+        #[arg(short, long, value_name = "WORLD_FILE")]
+        world: String,
+        /// Directory containing the .brain files taking part
+        #[arg(short, long, value_name = "BRAINS_DIRECTORY")]
+        brains: String,
+        /// Games played by each pair of brains, swapping colours every other game
+        #[arg(short, long, value_name = "NUMBER_OF_GAMES", default_value_t = 2, value_parser = at_least_one())]
+        games: usize
+    },
+    /// Prints a random arena, mirrored so that neither colour has an advantage
+    Generate {
+        /// Number of cells in each row
+        #[arg(long, value_name = "WIDTH", default_value_t = 40)]
+        width: usize,
+        /// Number of rows, rounded up to an even number
+        #[arg(long, value_name = "HEIGHT", default_value_t = 40)]
+        height: usize,
+        /// Chance for each cell to be an obstacle, from 0 to 1
+        #[arg(long, value_name = "DENSITY", default_value_t = 0.1, value_parser = probability)]
+        obstacle_density: f64,
+        /// Number of food clusters on each half of the arena
+        #[arg(long, value_name = "CLUSTERS", default_value_t = 4)]
+        food_clusters: usize
+    },
+    /// Looks for errors and likely mistakes in a .brain file without playing
+    Check {
+        /// Brain to check
+        #[arg(value_name = "BRAIN_FILE")]
+        brain: String
+    }
+}
+
+// Refuses 0 for counts that cannot be empty, such as a number of games
+fn at_least_one() -> RangedU64ValueParser<usize> {
+    RangedU64ValueParser::new().range(1..)
+}
+
+// Accepts a probability, from 0 to 1 included
+fn probability(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(p) if (0.0..=1.0).contains(&p) => Ok(p),
+        Ok(_) => Err(String::from("must be between 0 and 1")),
+        Err(e) => Err(e.to_string())
+    }
+}
+
+// Exits after printing why the games could not be played
+fn or_exit<T>(result: Result<T, SimulationError>) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(1)
+    })
+}
+
+// Prints results, or why the games could not be played
+fn print<T: Display + Serialize>(result: Result<T, SimulationError>, format: Format) {
+    let result = or_exit(result);
+    match format {
+        Format::Text => println!("{}", result),
+        Format::Json => println!("{}", serde_json::to_string(&result).expect("could not serialize results"))
+    }
+}
+
+// Runs f, giving it a callback that updates a progress bar on stderr if enabled
+// The bar is redrawn a few times per second at most
+fn with_progress<T>(enabled: bool, f: impl FnOnce(Option<&Progress>) -> T) -> T {
+    if !enabled {
+        return f(None)
+    }
+    let bar = ProgressBar::with_draw_target(None, ProgressDrawTarget::stderr_with_hz(4));
+    let result = f(Some(&|done, total| {
+        bar.set_length(total as u64);
+        bar.set_position(done as u64)
+    }));
+    bar.finish_and_clear();
+    result
+}
+
+// Lists the .brain files of a directory, sorted so that tournaments are reproducible
+fn brains_in(directory: &str) -> Result<Vec<String>, SimulationError> {
+    let mut brains: Vec<String> = fs::read_dir(directory)
+        .map_err(|error| SimulationError::Io { path: String::from(directory), error })?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "brain"))
+        .map(|path| path.to_string_lossy().into_owned())
+        .collect();
+    brains.sort();
+    Ok(brains)
 }
 
 fn main() {
     let args = Args::parse();
+    let mut rules = SimulationRules::default();
+    if let Some(move_cooldown) = args.move_cooldown {
+        rules.move_cooldown = move_cooldown
+    }
+
+    match args.command {
+        Some(Command::Tournament { world, brains, games }) => {
+            let result = brains_in(&brains).and_then(|brains| with_progress(args.progress, |progress| {
+                tournament(world, brains, games, args.ticks, args.seed, rules, progress)
+            }));
+            print(result, args.format);
+            return
+        }
+        Some(Command::Generate { width, height, obstacle_density, food_clusters }) => {
+            let seed = args.seed.unwrap_or_else(rand::random);
+            print!("{}", random_world(width, height, seed, obstacle_density, food_clusters));
+            return
+        }
+        Some(Command::Check { brain }) => {
+            let (errors, warnings) = check_brain(&brain);
+            for e in &errors {
+                println!("{}: error: {}", brain, e)
+            }
+            for w in &warnings {
+                println!("{}: warning: {}", brain, w)
+            }
+            println!("{}: {} error(s), {} warning(s)", brain, errors.len(), warnings.len());
+            if !errors.is_empty() {
+                exit(1)
+            }
+            return
+        }
+        None => ()
+    }
 
-    if let Some(games) = args.games {
-        get_average_score(args.world, (args.red_brain, args.black_brain), games, args.ticks);
+    if let Some(brain) = args.disassemble {
+        match load_instructionset(&brain) {
+            Ok(instructions) => print!("{}", disassemble(&instructions)),
+            Err(errors) => {
+                for e in errors {
+                    eprintln!("{}", e)
+                }
+                exit(1)
+            }
+        }
+        return
+    }
+
+    // Those are required by clap whenever no subcommand is given
+    let world = args.world.unwrap();
+    let brains = (args.red_brain.unwrap(), args.black_brain.unwrap());
+    let simulation = or_exit(Simulation::with_rules(&world, &brains.0, &brains.1, args.seed, rules));
+    // Playing a brain against a copy of itself is most likely a mistake
+    if brains.0 != brains.1 && simulation.same_brains() {
+        eprintln!("warning: {} and {} contain the same program", brains.0, brains.1)
+    }
+    if args.bench {
+        print(Ok(bench(simulation, args.ticks)), args.format)
+    } else if let Some(games) = args.games {
+        let result = with_progress(args.progress, |progress| {
+            get_average_score(world, brains, games, args.ticks, args.seed, rules, progress)
+        });
+        print(result, args.format);
     } else {
-        run(args.world, (args.red_brain, args.black_brain), args.ticks)
+        let result = with_progress(args.progress, |progress| play(simulation, args.ticks, progress));
+        print(Ok(result), args.format)
     }
 }
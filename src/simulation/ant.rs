@@ -1,13 +1,18 @@
 use std::fmt::{Debug};
 use std::rc::Rc;
 use rand::Rng;
+use rand::rngs::StdRng;
 use crate::simulation::instruction::{SenseDirection, TurnDirection};
 use crate::simulation::map::AntRef;
 use super::instruction::{InstructionSet, Instruction, Instruction::*};
-use super::map::Map;
+use super::map::{Map, neighbour, MARKER_COUNT};
+use super::replay::Event;
+use super::rules::SimulationRules;
+use serde::Serialize;
 
-#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Default, Serialize)]
 pub enum Colour {
+    #[default]
     Red,
     Black
 }
@@ -25,22 +30,31 @@ impl Colour {
         }
     }
 }
-impl Default for Colour {
-    fn default() -> Self { Self::Red }
-}
-#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Default, Serialize)]
 pub enum CardinalDirection {
     West,
+    #[default]
     East,
     NorthWest,
     NorthEast,
     SouthWest,
     SouthEast
 }
-impl Default for CardinalDirection {
-    fn default() -> Self { Self::East }
-}
 impl CardinalDirection {
+    // Every direction, clockwise starting from the east
+    pub const ALL: [Self; 6] = [
+        Self::East,
+        Self::SouthEast,
+        Self::SouthWest,
+        Self::West,
+        Self::NorthWest,
+        Self::NorthEast
+    ];
+
+    pub fn iter() -> impl Iterator<Item = Self> {
+        Self::ALL.into_iter()
+    }
+
     pub fn right(self) -> Self {
         match self {
             Self::West => Self::NorthWest,
@@ -62,6 +76,32 @@ impl CardinalDirection {
             Self::NorthWest => Self::West
         }
     }
+
+    pub fn opposite(self) -> Self {
+        match self {
+            Self::West => Self::East,
+            Self::East => Self::West,
+            Self::NorthWest => Self::SouthEast,
+            Self::SouthEast => Self::NorthWest,
+            Self::NorthEast => Self::SouthWest,
+            Self::SouthWest => Self::NorthEast
+        }
+    }
+}
+
+// One instruction executed by a traced ant, see Simulation::trace_ant
+// The ant's state is the one after the instruction, and next_instruction is where it
+// jumped to, or the following instruction if it did not jump
+#[derive(Debug, Clone)]
+pub struct TraceStep {
+    pub tick: usize,
+    pub ant: usize,
+    pub instruction_index: usize,
+    pub instruction: Instruction,
+    pub position: (usize, usize),
+    pub direction: CardinalDirection,
+    pub food: u32,
+    pub next_instruction: usize
 }
 
 // Completely represents one ant
@@ -70,108 +110,153 @@ pub struct Ant {
     pub id: usize,
     pub colour: Colour,
     pub position: (usize, usize),
-    pub has_food: bool,
+    pub food: u32,
 
     current_instruction: usize,
-    cooldown: usize,
-    direction: CardinalDirection
+    cooldown: u8,
+    direction: CardinalDirection,
+    // Ticks the ant has lived through
+    age: u32
 }
 impl Ant {
     // Creates a new ant of the given colour
+    // Red ants face east and black ones face west, so that in a world mirrored
+    // through its centre each ant faces the mirror of its counterpart's direction
     pub fn new(id: usize, colour: Colour, position: (usize, usize)) -> Self {
         Self {
             id,
             colour,
             position,
-            has_food: false,
+            food: 0,
 
             current_instruction: 0,
             cooldown: 0,
-            direction: CardinalDirection::default(),
+            direction: match colour {
+                Colour::Red => CardinalDirection::East,
+                Colour::Black => CardinalDirection::West
+            },
+            age: 0
         }
     }
 
+    pub fn has_food(&self) -> bool {
+        self.food > 0
+    }
+
+    pub fn on_cooldown(&self) -> bool {
+        self.cooldown > 0
+    }
+
+    pub fn direction(&self) -> CardinalDirection {
+        self.direction
+    }
+
+    pub fn age(&self) -> u32 {
+        self.age
+    }
+
+    // Index of the instruction the ant executes next
+    pub fn current_instruction(&self) -> usize {
+        self.current_instruction
+    }
+
     // Processes one tick, executing a command if the ant is off cooldown, and
-    // reducing said cooldown by 1
-    pub fn process_tick(ant: AntRef, map: &mut Map, instructions: &InstructionSet) {
+    // reducing said cooldown by 1 otherwise
+    // An ant that moves on tick t sets its cooldown to the rules' move_cooldown, spends
+    // ticks t+1 to t+move_cooldown counting it down, and acts again on tick t+move_cooldown+1
+    pub fn process_tick(ant: AntRef, map: &mut Map, instructions: &InstructionSet, rng: &mut StdRng, rules: &SimulationRules) -> Option<Event> {
+        (*ant).borrow_mut().age += 1;
         if (*ant).borrow().cooldown == 0 {
-            let current_instruction = instructions.get((*ant).borrow().current_instruction)
-                .expect("Instruction count is out of bounds");
-            Self::exec(Rc::clone(&ant), current_instruction, map);
+            // Loaded brains never go past their last instruction, but a program built
+            // without the parser can, the ant then doing nothing
+            let current_instruction = instructions.get((*ant).borrow().current_instruction)?;
+            Self::exec(Rc::clone(&ant), current_instruction, map, rng, rules)
         } else {
-            (*ant).borrow_mut().cooldown -= 1
+            (*ant).borrow_mut().cooldown -= 1;
+            None
         }
     }
 
     // Executes a given instruction, ant's state and map
     // The instruction can change the ant's state
-    // Returns the index of the next instruction
-    fn exec(ant: AntRef, instruction: &Instruction, map: &mut Map) -> () {
-         let jump_instruction = match *instruction {
+    // Flip draws from the simulation's rng so that seeded games are reproducible
+    // Returns the change the instruction made to the simulation, if any
+    fn exec(ant: AntRef, instruction: &Instruction, map: &mut Map, rng: &mut StdRng, rules: &SimulationRules) -> Option<Event> {
+        let id = (*ant).borrow().id;
+        let (jump_instruction, event) = match *instruction {
             Sense(dir, true_label, false_label, cond) => {
                 // Calculates the target cell's index
                 let cell = (*ant).borrow().target_cell(dir);
                 // Then checks the given condition and change the current instruction
                 // accordingly
-                Some(if map.check_condition(cond, (*ant).borrow().colour, cell) {
+                // Nothing can be sensed outside of the map
+                let holds = cell.is_some_and(|cell| map.check_condition(cond, (*ant).borrow().colour, cell));
+                (Some(if holds {
                     true_label
                 } else {
                     false_label
-                })
+                }), None)
             },
             Mark(i) => {
-                map.mark_pheromone((*ant).borrow().position, i, (*ant).borrow().colour);
-                None
+                let (cell, colour) = ((*ant).borrow().position, (*ant).borrow().colour);
+                map.mark_pheromone(cell, i, colour);
+                (None, (i < MARKER_COUNT).then_some(Event::Marked { colour, cell, marker: i }))
             },
             Unmark(i) => {
-                map.unmark_pheromone((*ant).borrow().position, i, (*ant).borrow().colour);
-                None
+                let (cell, colour) = ((*ant).borrow().position, (*ant).borrow().colour);
+                map.unmark_pheromone(cell, i, colour);
+                (None, (i < MARKER_COUNT).then_some(Event::Unmarked { colour, cell, marker: i }))
             },
             Pickup(fail_label) => {
-                if !(*ant).borrow().has_food && map.pickup_food((*ant).borrow().position) {
-                    (*ant).borrow_mut().has_food = true;
-                    None
+                let cell = (*ant).borrow().position;
+                if (*ant).borrow().food < rules.food_capacity && map.pickup_food(cell) {
+                    (*ant).borrow_mut().food += 1;
+                    (None, Some(Event::PickedUp { ant: id, cell }))
                 } else {
-                    Some(fail_label)
+                    (Some(fail_label), None)
                 }
             },
             Drop => {
-                if (*ant).borrow().has_food {
-                    map.drop_food((*ant).borrow().position);
+                let cell = (*ant).borrow().position;
+                // Everything carried is dropped at once
+                let amount = (*ant).borrow().food;
+                if amount > 0 {
+                    map.drop_food(cell, amount);
                 }
-                (*ant).borrow_mut().has_food = false;
-                None
-            },
-            Turn(TurnDirection::Left) => {
-                let next_direction = (*ant).borrow().direction.left();
-                (*ant).borrow_mut().direction = next_direction;
-                None
+                (*ant).borrow_mut().food = 0;
+                (None, (amount > 0).then_some(Event::Dropped { ant: id, cell, amount }))
             },
-            Turn(TurnDirection::Right) => {
-                let next_direction = (*ant).borrow().direction.right();
+            Turn(turn) => {
+                let direction = (*ant).borrow().direction;
+                let next_direction = match turn {
+                    TurnDirection::Left => direction.left(),
+                    TurnDirection::Right => direction.right(),
+                    TurnDirection::Random if rng.gen_bool(0.5) => direction.left(),
+                    TurnDirection::Random => direction.right(),
+                    TurnDirection::Back => direction.opposite()
+                };
                 (*ant).borrow_mut().direction = next_direction;
-                None
+                (None, Some(Event::Turned { ant: id, direction: next_direction }))
             },
             Move(fail_label) => {
                 let from = (*ant).borrow().position;
                 let to = (*ant).borrow().target_cell(SenseDirection::Ahead);
-                if map.move_to(from, to) {
+                if let Some(to) = to.filter(|to| map.move_to(from, *to)) {
                     (*ant).borrow_mut().position = to;
-                    (*ant).borrow_mut().cooldown = 14;
-                    None
+                    (*ant).borrow_mut().cooldown = rules.move_cooldown;
+                    (None, Some(Event::Moved { ant: id, from, to }))
                 } else {
-                    Some(fail_label)
+                    (Some(fail_label), None)
                 }
             },
             Flip(p, success_label, failure_label) => {
-                let rng = rand::thread_rng().gen_range(0..p);
-                Some(if rng == 0 {
+                (Some(if rng.gen_range(0..p) == 0 {
                     success_label
                 } else {
                     failure_label
-                })
+                }), None)
             },
-            Goto(label) => Some(label)
+            Goto(label) => (Some(label), None)
         };
 
         if let Some(instruction) = jump_instruction {
@@ -179,23 +264,184 @@ impl Ant {
         } else {
             (*ant).borrow_mut().current_instruction += 1
         }
+        event
     }
 
-    fn target_cell(&self, direction: SenseDirection) -> (usize, usize) {
-        let (x, y) = self.position;
-        let sense_direction = match direction {
-            SenseDirection::Right => self.direction.right(),
-            SenseDirection::Left => self.direction.left(),
-            SenseDirection::Here => return self.position,
-            _ => self.direction
+    // Returns None when the target cell would be out of the map
+    // Going straight only ever moves away from an edge once it is crossed, so
+    // checking the last cell of a multi-step sense is enough
+    fn target_cell(&self, direction: SenseDirection) -> Option<(usize, usize)> {
+        let (sense_direction, steps) = match direction {
+            SenseDirection::Right => (self.direction.right(), 1),
+            SenseDirection::Left => (self.direction.left(), 1),
+            SenseDirection::Here => return Some(self.position),
+            SenseDirection::AheadN(n) => (self.direction, n),
+            SenseDirection::Ahead => (self.direction, 1)
         };
-        match sense_direction {
-            CardinalDirection::West => (x-1, y),
-            CardinalDirection::NorthEast => (x+1, y-1),
-            CardinalDirection::NorthWest => (x-1, y-1),
-            CardinalDirection::East => (x+1, y),
-            CardinalDirection::SouthEast => (x+1, y+1),
-            CardinalDirection::SouthWest => (x-1, y+1)
+        (0..steps).try_fold(self.position, |cell, _| neighbour(cell, sense_direction))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use crate::simulation::instruction::Cond;
+
+    // A row of empty cells with a red ant at its west end, facing east
+    const ROW: &str = "row\n20\n1\n+ . . . . . . . . . . . . . . . . . . -\n";
+
+    // Runs the red ant of a world for a number of ticks, the black one staying still
+    // Returns the events caused along with the tick they happened on, and the ant
+    fn run_red(world: &str, program: &[Instruction], rules: SimulationRules, ticks: usize) -> (Vec<(usize, Event)>, AntRef) {
+        let (mut map, ants) = Map::from_world_string(world, None).unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+        let red = Rc::clone(&ants[0]);
+        let program = program.to_vec();
+        let events = (0..ticks)
+            .filter_map(|tick| {
+                Ant::process_tick(Rc::clone(&red), &mut map, &program, &mut rng, &rules)
+                    .map(|event| (tick, event))
+            })
+            .collect();
+        (events, red)
+    }
+
+    // An empty map with a single red and black nest in opposite corners
+    fn open_world(width: usize, height: usize) -> String {
+        let mut cells = vec!["."; width * height];
+        cells[0] = "+";
+        cells[width * height - 1] = "-";
+        let rows: Vec<String> = cells.chunks(width).map(|row| row.join(" ")).collect();
+        format!("open\n{}\n{}\n{}\n", width, height, rows.join("\n"))
+    }
+
+    #[test]
+    fn opposite_directions_are_half_a_turn_away() {
+        for d in CardinalDirection::iter() {
+            assert_eq!(d.opposite().opposite(), d);
+            assert_eq!(d.opposite(), d.right().right().right());
+            assert_ne!(d.opposite(), d);
+        }
+    }
+
+    #[test]
+    fn ants_and_map_agree_on_neighbours() {
+        let (map, _) = Map::from_world_string(&open_world(8, 8), None).unwrap();
+        for cell in [(1, 1), (4, 2), (3, 3), (6, 5), (2, 6)] {
+            for (i, around) in map.surroundings(cell).into_iter().enumerate() {
+                let (_, around) = around.expect("interior cells have six neighbours");
+                let mut ant = Ant::new(0, Colour::Red, cell);
+                ant.direction = CardinalDirection::ALL[i];
+                assert_eq!(ant.target_cell(SenseDirection::Ahead), Some(around));
+                assert_eq!(neighbour(around, ant.direction.opposite()), Some(cell));
+            }
+        }
+    }
+
+    #[test]
+    fn moving_or_sensing_past_the_edge_fails() {
+        let program = [
+            Turn(TurnDirection::Back),
+            // West of the first column
+            Move(3),
+            Drop,
+            Turn(TurnDirection::Right),
+            // North of the first row
+            Sense(SenseDirection::Ahead, 5, 6, Cond::Rock),
+            Drop,
+            Move(7),
+            Goto(7)
+        ];
+        let (events, ant) = run_red(ROW, &program, SimulationRules::default(), 5);
+        assert_eq!(events, vec![
+            (0, Event::Turned { ant: 0, direction: CardinalDirection::West }),
+            (2, Event::Turned { ant: 0, direction: CardinalDirection::NorthWest })
+        ]);
+        assert_eq!(ant.borrow().position, (0, 0));
+        assert_eq!(ant.borrow().current_instruction, 7);
+    }
+
+    #[test]
+    fn ants_sense_two_cells_ahead() {
+        let program = [
+            Sense(SenseDirection::Ahead, 5, 1, Cond::Food),
+            Sense(SenseDirection::AheadN(2), 2, 5, Cond::Food),
+            Sense(SenseDirection::AheadN(4), 3, 5, Cond::FoeHome),
+            // Past the east end of the row
+            Sense(SenseDirection::AheadN(5), 5, 4, Cond::Rock),
+            Goto(4),
+            Goto(5)
+        ];
+        let (_, ant) = run_red("row\n5\n1\n+ . 3 . -\n", &program, SimulationRules::default(), 10);
+        assert_eq!(ant.borrow().current_instruction, 4);
+    }
+
+    #[test]
+    fn random_turns_are_stable_under_a_seed() {
+        let turns = || run_red(ROW, &[Turn(TurnDirection::Random), Goto(0)], SimulationRules::default(), 16).0;
+        let events = turns();
+        assert_eq!(turns(), events);
+
+        let mut direction = CardinalDirection::East;
+        for (_, event) in events {
+            let Event::Turned { direction: turned, .. } = event else { panic!("{:?}", event) };
+            assert!(turned == direction.left() || turned == direction.right());
+            direction = turned;
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn ants_carry_up_to_food_capacity() {
+        let rules = SimulationRules { food_capacity: 3, move_cooldown: 0, ..Default::default() };
+        let program = [Move(0), Pickup(5), Pickup(5), Pickup(5), Pickup(6), Drop, Goto(6)];
+        let (events, ant) = run_red("food\n4\n1\n+ 5 . -\n", &program, rules, 6);
+
+        let picked_up = events.iter().filter(|(_, e)| matches!(e, Event::PickedUp { .. })).count();
+        assert_eq!(picked_up, 3);
+        assert_eq!(ant.borrow().food, 3);
+        // The fourth PickUp failed, jumping over the Drop
+        assert_eq!(ant.borrow().current_instruction, 6);
+    }
+
+    #[test]
+    fn move_cooldown_is_a_rule() {
+        let program = [Move(4), Move(4), Move(4), Move(4), Goto(4)];
+        let move_ticks = |move_cooldown| {
+            let rules = SimulationRules { move_cooldown, ..Default::default() };
+            let (events, ant) = run_red(ROW, &program, rules, 16);
+            assert_eq!(ant.borrow().position, (4, 0));
+            events.into_iter().map(|(tick, _)| tick).collect::<Vec<usize>>()
+        };
+        assert_eq!(move_ticks(0), vec![0, 1, 2, 3]);
+        assert_eq!(move_ticks(3), vec![0, 4, 8, 12]);
+    }
+
+    #[test]
+    fn ants_mark_and_sense_high_markers() {
+        let program = [
+            Mark(12),
+            Sense(SenseDirection::Here, 2, 5, Cond::Marker(12)),
+            Sense(SenseDirection::Here, 5, 3, Cond::Marker(11)),
+            Unmark(12),
+            Sense(SenseDirection::Here, 5, 6, Cond::Marker(12)),
+            Goto(5),
+            Goto(6)
+        ];
+        let (events, ant) = run_red(ROW, &program, SimulationRules::default(), 10);
+        assert_eq!(ant.borrow().current_instruction, 6);
+        assert_eq!(events, vec![
+            (0, Event::Marked { colour: Colour::Red, cell: (0, 0), marker: 12 }),
+            (3, Event::Unmarked { colour: Colour::Red, cell: (0, 0), marker: 12 })
+        ]);
+    }
+
+    #[test]
+    fn move_costs_move_cooldown_ticks() {
+        let (events, _) = run_red(ROW, &[Move(1), Turn(TurnDirection::Left), Goto(0)], SimulationRules::default(), 16);
+        assert_eq!(events, vec![
+            (0, Event::Moved { ant: 0, from: (0, 0), to: (1, 0) }),
+            (15, Event::Turned { ant: 0, direction: CardinalDirection::NorthEast })
+        ]);
+    }
+}
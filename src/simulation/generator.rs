@@ -0,0 +1,134 @@
+use rand::Rng;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use super::ant::CardinalDirection;
+use super::map::neighbour;
+
+// Smallest width and height leaving room for both nests inside the border
+const MIN_SIZE: usize = 8;
+// Units of food on each cell of a food cluster
+const CLUSTER_FOOD: char = '5';
+
+// Generates a random arena in the .world format
+// The map is surrounded by obstacles, and every cell is mirrored through the centre of
+// the map, the red nest becoming the black one, so that neither colour has an advantage
+// Mirroring (x, y) to (width-1-x, height-1-y) only keeps neighbours next to each other
+// if rows keep their parity, so an odd height is rounded up
+// Nests are made of a cell and its six neighbours, and no obstacle is placed next to them
+// obstacle_density is the chance for each cell to be an obstacle, values outside of
+// 0.0..=1.0 being clamped. It must be a number: NaN and infinities panic
+pub fn random_world(width: usize, height: usize, seed: u64, obstacle_density: f64, food_clusters: usize) -> String {
+    assert!(obstacle_density.is_finite(), "obstacle density must be a finite probability, got {}", obstacle_density);
+    let width = width.max(MIN_SIZE);
+    let height = (height + height % 2).max(MIN_SIZE);
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut cells = vec!['.'; width * height];
+    let index = |(x, y): (usize, usize)| y * width + x;
+    let mirror = |(x, y): (usize, usize)| (width - 1 - x, height - 1 - y);
+    let inside = |(x, y): (usize, usize)| x > 0 && y > 0 && x < width - 1 && y < height - 1;
+    // A cell and its neighbours which are not on the border
+    let around = |cell: (usize, usize)| {
+        std::iter::once(cell)
+            .chain(CardinalDirection::iter().filter_map(move |d| neighbour(cell, d)))
+            .filter(move |c| inside(*c))
+    };
+
+    for y in 0..height {
+        for x in 0..width {
+            if !inside((x, y)) {
+                cells[index((x, y))] = '#'
+            }
+        }
+    }
+
+    let nest = (width / 4, height / 4);
+    for cell in around(nest) {
+        cells[index(cell)] = '+';
+        cells[index(mirror(cell))] = '-';
+    }
+    // Cells on which nothing may be placed so that nests are never walled in
+    let near_nest = |cell: (usize, usize)| {
+        around(cell).chain(around(mirror(cell)))
+            .any(|c| around(nest).any(|n| n == c))
+    };
+
+    // Only the first half of the map is drawn, the other one being its mirror
+    let half: Vec<(usize, usize)> = (0..width * height / 2)
+        .map(|i| (i % width, i / width))
+        .filter(|cell| inside(*cell) && !near_nest(*cell))
+        .collect();
+    for cell in &half {
+        if rng.gen_bool(obstacle_density.clamp(0.0, 1.0)) {
+            cells[index(*cell)] = '#';
+            cells[index(mirror(*cell))] = '#';
+        }
+    }
+    for _ in 0..food_clusters {
+        if half.is_empty() {
+            break
+        }
+        let centre = half[rng.gen_range(0..half.len())];
+        let cluster: Vec<(usize, usize)> = around(centre)
+            .filter(|c| cells[index(*c)] == '.' && !near_nest(*c))
+            .collect();
+        for cell in cluster {
+            cells[index(cell)] = CLUSTER_FOOD;
+            cells[index(mirror(cell))] = CLUSTER_FOOD;
+        }
+    }
+
+    let mut world = format!("random {}\n{}\n{}\n", seed, width, height);
+    for (y, row) in cells.chunks(width).enumerate() {
+        if !y.is_multiple_of(2) {
+            world.push(' ')
+        }
+        let row: Vec<String> = row.iter().map(|c| c.to_string()).collect();
+        world.push_str(&row.join(" "));
+        world.push('\n');
+    }
+    world
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::ant::Colour;
+    use crate::simulation::map::{Cell, Map};
+
+    #[test]
+    fn generated_worlds_are_point_symmetric() {
+        let world = random_world(21, 15, 42, 0.15, 4);
+        assert_eq!(random_world(21, 15, 42, 0.15, 4), world);
+        let (map, ants) = Map::from_world_string(&world, None).unwrap();
+        let (width, height) = map.size();
+        assert_eq!((width, height), (21, 16));
+
+        for ((x, y), cell) in map.cells() {
+            let mirrored = &map[(width - 1 - x, height - 1 - y)];
+            match (cell, mirrored) {
+                (Cell::Nest { colour: Colour::Red, .. }, Cell::Nest { colour: Colour::Black, .. })
+                | (Cell::Nest { colour: Colour::Black, .. }, Cell::Nest { colour: Colour::Red, .. })
+                | (Cell::Obstacle, Cell::Obstacle) => (),
+                (Cell::Empty { food, .. }, Cell::Empty { food: mirrored_food, .. }) => assert_eq!(food, mirrored_food),
+                _ => panic!("({}, {}) is not mirrored", x, y)
+            }
+        }
+
+        // A single nest per colour, made of a cell and its six neighbours
+        let red: Vec<(usize, usize)> = map.cells()
+            .filter(|(_, cell)| matches!(cell, Cell::Nest { colour: Colour::Red, .. }))
+            .map(|(position, _)| position)
+            .collect();
+        assert_eq!(red.len(), 7);
+        assert_eq!(ants.len(), 14);
+        assert!(red.iter().any(|cell| {
+            CardinalDirection::iter().all(|d| neighbour(*cell, d).is_some_and(|n| red.contains(&n)))
+        }));
+    }
+
+    #[test]
+    #[should_panic(expected = "finite probability")]
+    fn obstacle_density_must_be_a_number() {
+        random_world(20, 20, 0, f64::NAN, 2);
+    }
+}
@@ -1,46 +1,143 @@
-use std::fs::File;
-use std::io::{self, BufRead, BufReader};
-use std::path::Path;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
 use std::collections::HashMap;
-use regex::Regex;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use serde::{Deserialize, Serialize};
+use super::map::MARKER_COUNT;
 
 type Label = usize;
 
-#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
+// Bumped whenever Instruction or the cache header changes, so that older .brainc files
+// are parsed again
+const BRAINC_VERSION: u32 = 2;
+// Gives each .brainc file being written a temporary name of its own
+static CACHE_WRITES: AtomicUsize = AtomicUsize::new(0);
+
+// Everything that can go wrong while loading a .brain file
+// Line numbers start at 1, as in a text editor
+#[derive(Debug)]
+pub enum BrainParseError {
+    Io(io::Error),
+    UnknownInstruction { line: usize, instruction: String },
+    MissingArgument { line: usize, instruction: String },
+    InvalidArgument { line: usize, argument: String },
+    NotAnInteger { line: usize, argument: String },
+    UndefinedLabel { line: usize, label: String },
+    JumpOutOfBounds { line: usize, target: Label },
+    // The last instruction can be followed by one that does not exist
+    FallsOffEnd { line: usize },
+    EmptyProgram
+}
+impl Display for BrainParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "could not read brain file: {}", e),
+            Self::UnknownInstruction { line, instruction } => write!(f, "line {}: unknown instruction {}", line, instruction),
+            Self::MissingArgument { line, instruction } => write!(f, "line {}: missing argument on {} instruction", line, instruction),
+            Self::InvalidArgument { line, argument } => write!(f, "line {}: invalid argument {}", line, argument),
+            Self::NotAnInteger { line, argument } => write!(f, "line {}: argument {} is not an integer", line, argument),
+            Self::UndefinedLabel { line, label } => write!(f, "line {}: use of undefined label {}", line, label),
+            Self::JumpOutOfBounds { line, target } => write!(f, "line {}: jump to {} is past the last instruction", line, target),
+            Self::FallsOffEnd { line } => write!(f, "line {}: execution can go past the last instruction", line),
+            Self::EmptyProgram => write!(f, "brain has no instruction")
+        }
+    }
+}
+impl std::error::Error for BrainParseError {}
+impl From<io::Error> for BrainParseError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+// Things that do not prevent a brain from loading but are most likely mistakes
+#[derive(Debug)]
+pub enum BrainWarning {
+    // Instructions no path from the first instruction leads to
+    Unreachable { first_line: usize, last_line: usize },
+    // Gotos jumping to each other forever, freezing any ant that reaches them
+    GotoLoop { line: usize }
+}
+impl Display for BrainWarning {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unreachable { first_line, last_line } if first_line == last_line => write!(f, "line {}: unreachable instruction", first_line),
+            Self::Unreachable { first_line, last_line } => write!(f, "lines {}-{}: unreachable instructions", first_line, last_line),
+            Self::GotoLoop { line } => write!(f, "line {}: Goto loops forever", line)
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Serialize, Deserialize)]
 pub enum SenseDirection {
     Ahead,
+    // Senses the cell a given number of steps ahead, written AheadN (Ahead2, Ahead3...)
+    AheadN(u8),
     Left,
     Right,
     Here
 }
-impl From<String> for SenseDirection {
-    fn from(s: String) -> Self {
-        match s.as_str() {
-            "Ahead" => Self::Ahead,
-            "LeftAhead" => Self::Left,
-            "RightAhead" => Self::Right,
-            "Here" => Self::Here,
-            _ => panic!("Not a valid SenseDirection")
+impl TryFrom<&str> for SenseDirection {
+    type Error = ();
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        match s {
+            "Ahead" => Ok(Self::Ahead),
+            "LeftAhead" => Ok(Self::Left),
+            "RightAhead" => Ok(Self::Right),
+            "Here" => Ok(Self::Here),
+            _ => match s.strip_prefix("Ahead").map(|n| n.parse::<u8>()) {
+                Some(Ok(n)) if n > 0 => Ok(Self::AheadN(n)),
+                _ => Err(())
+            }
         }
     }
 }
 
-#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
+impl Display for SenseDirection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Ahead => write!(f, "Ahead"),
+            Self::AheadN(n) => write!(f, "Ahead{}", n),
+            Self::Left => write!(f, "LeftAhead"),
+            Self::Right => write!(f, "RightAhead"),
+            Self::Here => write!(f, "Here")
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Serialize, Deserialize)]
 pub enum TurnDirection {
     Left,
-    Right
+    Right,
+    // Either left or right, drawn from the simulation's rng
+    Random,
+    // Half a turn, facing the opposite direction
+    Back
 }
-impl From<String> for TurnDirection {
-    fn from(s: String) -> Self {
-        match s.as_str() {
-            "Left" => Self::Left,
-            "Right" => Self::Right,
-            _ => panic!("Not a valid TurnDirection")
+impl TryFrom<&str> for TurnDirection {
+    type Error = ();
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        match s {
+            "Left" => Ok(Self::Left),
+            "Right" => Ok(Self::Right),
+            "Random" => Ok(Self::Random),
+            "Back" => Ok(Self::Back),
+            _ => Err(())
         }
     }
 }
 
-#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
+impl Display for TurnDirection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Serialize, Deserialize)]
 pub enum Cond {
     Friend,
     Foe,
@@ -53,27 +150,38 @@ pub enum Cond {
     Home,
     FoeHome
 }
-impl From<(String, Option<usize>)> for Cond {
-    fn from((s, i): (String, Option<usize>)) -> Self {
-        let mut instruction_parts = s.split(" ");
-        match instruction_parts.next().unwrap() {
-            "Friend" => Self::Friend,
-            "Foe" => Self::Foe,
-            "FriendWithFood" => Self::FriendWithFood,
-            "FoeWithFood" => Self::FoeWithFood,
-            "Food" => Self::Food,
-            "Rock" => Self::Rock,
-            "Marker" => Self::Marker(i.expect("Missing argument on Marker condition")),
-            "FoeMarker" => Self::FoeMarker,
-            "Home" => Self::Home,
-            "FoeHome" => Self::FoeHome,
-            _ => panic!("Not a valid TurnDirection")
+// The marker index is only needed (and required) by the Marker condition
+impl TryFrom<(&str, Option<usize>)> for Cond {
+    type Error = ();
+
+    fn try_from((s, i): (&str, Option<usize>)) -> Result<Self, Self::Error> {
+        match s {
+            "Friend" => Ok(Self::Friend),
+            "Foe" => Ok(Self::Foe),
+            "FriendWithFood" => Ok(Self::FriendWithFood),
+            "FoeWithFood" => Ok(Self::FoeWithFood),
+            "Food" => Ok(Self::Food),
+            "Rock" => Ok(Self::Rock),
+            "Marker" => i.map(Self::Marker).ok_or(()),
+            "FoeMarker" => Ok(Self::FoeMarker),
+            "Home" => Ok(Self::Home),
+            "FoeHome" => Ok(Self::FoeHome),
+            _ => Err(())
+        }
+    }
+}
+
+impl Display for Cond {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Marker(i) => write!(f, "Marker {}", i),
+            _ => write!(f, "{:?}", self)
         }
     }
 }
 
 // Le set d'instructions fourni dans le pdf du projet
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub enum Instruction {
     Sense(SenseDirection, Label, Label, Cond),
     Mark(usize),
@@ -85,155 +193,541 @@ pub enum Instruction {
     Flip(usize, Label, Label),
     Goto(Label)
 }
-impl From<(String, &HashMap<String, usize>)> for Instruction {
-    fn from((instr, label_map): (String, &HashMap<String, usize>)) -> Self {
-        let trimmed = instr.trim();
-        let mut instruction_parts = trimmed.split(" ");
-        let instruction_type = instruction_parts.next().unwrap();
+impl Instruction {
+    // Every instruction index this instruction can jump to
+    pub fn jump_targets(&self) -> Vec<Label> {
+        match *self {
+            Self::Sense(_, l1, l2, _) | Self::Flip(_, l1, l2) => vec![l1, l2],
+            Self::Pickup(l) | Self::Move(l) | Self::Goto(l) => vec![l],
+            _ => vec![]
+        }
+    }
+
+    // Whether the next instruction can be executed after this one
+    pub fn falls_through(&self) -> bool {
+        !matches!(self, Self::Sense(..) | Self::Flip(..) | Self::Goto(_))
+    }
+}
+// Jumps are written as resolved instruction indices rather than labels
+impl Display for Instruction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Sense(dir, l1, l2, cond) => write!(f, "Sense {} -> if {} else {} ({})", dir, l1, l2, cond),
+            Self::Mark(i) => write!(f, "Mark {}", i),
+            Self::Unmark(i) => write!(f, "Unmark {}", i),
+            Self::Pickup(l) => write!(f, "PickUp -> else {}", l),
+            Self::Drop => write!(f, "Drop"),
+            Self::Turn(dir) => write!(f, "Turn {}", dir),
+            Self::Move(l) => write!(f, "Move -> else {}", l),
+            Self::Flip(p, l1, l2) => write!(f, "Flip {} -> if {} else {}", p, l1, l2),
+            Self::Goto(l) => write!(f, "Goto -> {}", l)
+        }
+    }
+}
+// Parses one instruction line, given its line number (for error reporting)
+// and the label map built during the loader's first pass
+impl TryFrom<(usize, &str, &HashMap<String, usize>)> for Instruction {
+    type Error = BrainParseError;
+
+    fn try_from((line, instr, label_map): (usize, &str, &HashMap<String, usize>)) -> Result<Self, Self::Error> {
+        let mut instruction_parts = instr.split_whitespace();
+        let instruction_type = instruction_parts.next().unwrap_or_default();
+
+        let mut next_argument = || instruction_parts.next()
+            .ok_or_else(|| BrainParseError::MissingArgument {
+                line,
+                instruction: String::from(instruction_type)
+            });
+        let label = |s: &str| label_map.get(s)
+            .copied()
+            .ok_or_else(|| BrainParseError::UndefinedLabel {
+                line,
+                label: String::from(s)
+            });
+        let integer = |s: &str| s.parse::<usize>()
+            .map_err(|_| BrainParseError::NotAnInteger {
+                line,
+                argument: String::from(s)
+            });
+        let invalid = |s: &str| BrainParseError::InvalidArgument {
+            line,
+            argument: String::from(s)
+        };
+        // Markers past MARKER_COUNT do not exist
+        let marker = |s: &str| match integer(s)? {
+            i if i < MARKER_COUNT => Ok(i),
+            _ => Err(invalid(s))
+        };
+
         match instruction_type {
             "Sense" => {
-                let direction = SenseDirection::from(
-                    String::from(instruction_parts.next()
-                        .expect("Missing parameters to Sense instruction"))
-                );
-                let label1 = label_map.get(
-                    instruction_parts.next()
-                        .expect("Missing argument on Sense instruction")
-                ).expect("Use of an undefined label in Sense instruction");
-                let label2 = label_map.get(
-                    instruction_parts.next()
-                        .expect("Missing argument on Sense instruction")
-                ).expect("Use of an undefined label in Sense instruction");
-                let cond = Cond::from(
-                    (
-                    String::from(instruction_parts.next()
-                        .expect("Missing argument on Sense instruction")),
-                    instruction_parts.next()
-                        .map(|x| x.parse::<usize>().ok())
-                        .flatten()
-                    )
-                );
-                Instruction::Sense(direction, *label1, *label2, cond)
-            }
-            "Mark" => {
-                let i = instruction_parts.next()
-                    .expect("Missing argument on Mark instruction")
-                    .parse::<usize>()
-                    .expect("Argument on Mark instruction is not an integer");
-                Instruction::Mark(i)
-            }
-            "Unmark" => {
-                let i = instruction_parts.next()
-                    .expect("Missing argument on Unmark instruction")
-                    .parse::<usize>()
-                    .expect("Argument on Unmark instruction is not an integer");
-                Instruction::Unmark(i)
+                let direction = next_argument()?;
+                let direction = SenseDirection::try_from(direction)
+                    .map_err(|_| invalid(direction))?;
+                let label1 = label(next_argument()?)?;
+                let label2 = label(next_argument()?)?;
+                let cond = next_argument()?;
+                let marker = match cond {
+                    "Marker" => Some(marker(next_argument()?)?),
+                    _ => None
+                };
+                let cond = Cond::try_from((cond, marker))
+                    .map_err(|_| invalid(cond))?;
+                Ok(Instruction::Sense(direction, label1, label2, cond))
             }
-            "PickUp" => {
-                let label = label_map.get(
-                    instruction_parts.next()
-                        .expect("Missing argument on Pickup instruction")
-                ).expect("Use of an undefined label in Pickup instruction");
-                Instruction::Pickup(*label)
-            }
-            "Drop" => Instruction::Drop,
+            "Mark" => Ok(Instruction::Mark(marker(next_argument()?)?)),
+            "Unmark" => Ok(Instruction::Unmark(marker(next_argument()?)?)),
+            "PickUp" => Ok(Instruction::Pickup(label(next_argument()?)?)),
+            "Drop" => Ok(Instruction::Drop),
             "Turn" => {
-                let dir = TurnDirection::from(
-                    String::from(instruction_parts.next()
-                        .expect("Missing argument on Turn instruction"))
-                );
-                Instruction::Turn(dir)
-            }
-            "Move" => {
-                let label = label_map.get(
-                    instruction_parts.next()
-                        .expect("Missing argument on Move instruction")
-                ).expect("Use of an undefined label in Move instruction");
-                Instruction::Move(*label)
+                let dir = next_argument()?;
+                let dir = TurnDirection::try_from(dir)
+                    .map_err(|_| invalid(dir))?;
+                Ok(Instruction::Turn(dir))
             }
+            "Move" => Ok(Instruction::Move(label(next_argument()?)?)),
             "Flip" => {
-                let p = instruction_parts.next()
-                    .expect("Missing argument on Flip instruction")
-                    .parse::<usize>()
-                    .expect("Argument of Flip instruction is not an integer");
-                let label1 = label_map.get(
-                    instruction_parts.next()
-                        .expect("Missing argument on Flip instruction")
-                ).expect("Use of an undefined label in Flip instruction");
-                let label2 = label_map.get(
-                    instruction_parts.next()
-                        .expect("Missing argument on Flip instruction")
-                ).expect("Use of an undefined label in Flip instruction");
-                Instruction::Flip(p, *label1, *label2)
-            }
-            "Goto" => {
-                let label = label_map.get(
-                    instruction_parts.next()
-                        .expect("Missing argument on Goto instruction")
-                ).expect("Use of an undefined label in Goto instruction");
-                Instruction::Goto(*label)
+                let argument = next_argument()?;
+                let p = integer(argument)?;
+                // Flip 0 would have no outcome to draw from
+                if p == 0 {
+                    return Err(invalid(argument))
+                }
+                let label1 = label(next_argument()?)?;
+                let label2 = label(next_argument()?)?;
+                Ok(Instruction::Flip(p, label1, label2))
             }
-            _ => panic!("Invalid instruction")
+            "Goto" => Ok(Instruction::Goto(label(next_argument()?)?)),
+            _ => Err(BrainParseError::UnknownInstruction {
+                line,
+                instruction: String::from(instruction_type)
+            })
         }
     }
 }
 
 pub type InstructionSet = Vec<Instruction>;
 
-pub fn load_instructionset(path: &str) -> InstructionSet {
-    fn read_lines<P>(filename: P) -> io::Result<io::Lines<BufReader<File>>>
-        where P: AsRef<Path>, {
-        let file = File::open(filename)?;
-        Ok(BufReader::new(file).lines())
-    }
-
-    let lines = read_lines(path)
-        .expect("Could not read the given .brain file")
-        .filter_map(|l| {
-            if let Ok(s) = l {
-                let s = s.trim();
-                if s.len() == 0 {
-                    None
-                } else {
-                    Some(String::from(s))
-                }
-            } else {
-                None
-            }
-        })
-        .enumerate();
-    let instruction_regex = Regex::new(r"Sense|Drop|Mark|Unmark|PickUp|Turn|Move|Flip|Goto").unwrap();
+// Prints a program one instruction per line, prefixed by its index
+pub fn disassemble(instructions: &InstructionSet) -> String {
+    instructions.iter()
+        .enumerate()
+        .map(|(i, instruction)| format!("{:04}: {}\n", i, instruction))
+        .collect()
+}
+
+// Removes a ';' or '#' comment (whole line or trailing) as well as surrounding spaces
+fn strip_comment(line: &str) -> &str {
+    line.split([';', '#'])
+        .next()
+        .unwrap_or_default()
+        .trim()
+}
+
+// Parses a brain, keeping going after an error so that all of them get reported
+// An instruction that failed to parse still takes its slot as None, which keeps the
+// indices of the following instructions in line with the labels
+// Returns each slot along with the line it was read from
+fn parse_brain(source: &str) -> (Vec<(Option<Instruction>, usize)>, Vec<BrainParseError>) {
+    // Every non-empty line along with its line number
+    // A line ending with ':' is a label, any other one an instruction
+    let lines: Vec<(usize, &str)> = source.lines()
+        .map(strip_comment)
+        .enumerate()
+        .map(|(i, line)| (i + 1, line))
+        .filter(|(_, line)| !line.is_empty())
+        .collect();
 
     // During a first pass, we simply care about the labels
     // This lets us create a Map of (label -> line) to make the implementation
     // way more efficient
     let mut labels_map: HashMap<String, usize> = HashMap::new();
-    let mut offset = 0;
-    for (i, line) in lines {
-        // The line is either an instruction or a label
-        if !instruction_regex.is_match(&line) {
-            let label = line.split(":").next().unwrap();
-            // Little manipulation so that the label is mapped to its location
-            // if the labels before it did not exist
+    let mut instruction_count = 0;
+    for (_, line) in &lines {
+        match line.strip_suffix(':') {
+            // A label is mapped to the index of the next instruction, no matter
+            // how many labels precede it
             // This lets us completely ignore labels later on, caring only about
             // the instruction's index
-            labels_map.insert(String::from(label), i - offset);
-            offset += 1;
+            Some(label) => {
+                labels_map.insert(String::from(label.trim_end()), instruction_count);
+            }
+            None => instruction_count += 1
         }
     }
     // We can then do a second pass, this time taking care of the
     // actual instructions
-    let lines = read_lines(path)
-        .expect("Could not read the given .brain file");
-    let mut instructions: InstructionSet = vec!();
-    for line in lines {
-        if let Ok(line) = line {
-            // The line is either an instruction or a label
-            if instruction_regex.is_match(&line) {
-                instructions.push(Instruction::from((line, &labels_map)));
+    let mut slots = vec!();
+    let mut errors = vec!();
+    for (line_number, line) in lines {
+        if line.ends_with(':') {
+            continue
+        }
+        match Instruction::try_from((line_number, line, &labels_map)) {
+            Ok(instruction) => slots.push((Some(instruction), line_number)),
+            Err(e) => {
+                errors.push(e);
+                slots.push((None, line_number))
+            }
+        }
+    }
+
+    // Labels placed after the last instruction resolve to an index that does not exist
+    for (instruction, line) in &slots {
+        let dangling = instruction.iter()
+            .flat_map(|instruction| instruction.jump_targets())
+            .filter(|target| *target >= slots.len());
+        for target in dangling {
+            errors.push(BrainParseError::JumpOutOfBounds { line: *line, target })
+        }
+    }
+    // Going on past the last instruction would be just as out of bounds
+    match slots.last() {
+        None => errors.push(BrainParseError::EmptyProgram),
+        Some((Some(instruction), line)) if instruction.falls_through() => {
+            errors.push(BrainParseError::FallsOffEnd { line: *line })
+        }
+        _ => ()
+    }
+
+    (slots, errors)
+}
+
+// Loads a .brain file, reporting every error found rather than only the first one
+pub fn load_instructionset(path: &str) -> Result<InstructionSet, Vec<BrainParseError>> {
+    parse_instructionset(&fs::read_to_string(path).map_err(|e| vec![e.into()])?)
+}
+
+// Same as load_instructionset, going through a .brainc cache next to the .brain file
+// The cache records the length and hash of the source it was built from, and is read
+// instead of parsing the source again only while both still match: timestamps are not
+// trusted, as copies and archives often restore files with an old one
+// The cache is only an optimisation: one that cannot be read or written is ignored
+pub fn load_cached_instructionset(path: &str) -> Result<InstructionSet, Vec<BrainParseError>> {
+    // Appended rather than replacing the extension, so that a.brain and a.txt get a cache each
+    let cache_path = PathBuf::from(format!("{}.brainc", path));
+    let source = fs::read_to_string(path).map_err(|e| vec![e.into()])?;
+    let (length, hash) = (source.len() as u64, source_hash(&source));
+    let cached = fs::read(&cache_path).ok()
+        .and_then(|bytes| bincode::deserialize::<(u32, u64, u64, InstructionSet)>(&bytes).ok());
+    if let Some((BRAINC_VERSION, cached_length, cached_hash, instructions)) = cached {
+        if (cached_length, cached_hash) == (length, hash) {
+            return Ok(instructions)
+        }
+    }
+
+    let instructions = parse_instructionset(&source)?;
+    if let Ok(bytes) = bincode::serialize(&(BRAINC_VERSION, length, hash, &instructions)) {
+        // Games played in parallel may write the same cache at once, so it is written
+        // aside then renamed, which never leaves a partly written cache behind
+        let temporary_path = PathBuf::from(format!(
+            "{}.{}.{}",
+            cache_path.display(),
+            std::process::id(),
+            CACHE_WRITES.fetch_add(1, Ordering::Relaxed)
+        ));
+        if fs::write(&temporary_path, bytes).and_then(|_| fs::rename(&temporary_path, &cache_path)).is_err() {
+            let _ = fs::remove_file(&temporary_path);
+        }
+    }
+    Ok(instructions)
+}
+
+// 64-bit FNV-1a of a brain's source, which unlike std's hashers stays the same across
+// Rust releases, so that caches outlive toolchain upgrades
+fn source_hash(source: &str) -> u64 {
+    source.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+// Parses the contents of a .brain file, reporting every error found
+pub fn parse_instructionset(source: &str) -> Result<InstructionSet, Vec<BrainParseError>> {
+    let (slots, errors) = parse_brain(source);
+    if errors.is_empty() {
+        Ok(slots.into_iter().filter_map(|(instruction, _)| instruction).collect())
+    } else {
+        Err(errors)
+    }
+}
+
+// Looks for mistakes in a .brain file without running it
+// Along with everything that prevents the brain from loading, such as execution going
+// past the last instruction, reports instructions that can never run and Gotos looping forever
+// Instructions that failed to parse are assumed to go on to the next one
+pub fn check_brain(path: &str) -> (Vec<BrainParseError>, Vec<BrainWarning>) {
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(e) => return (vec![e.into()], vec!())
+    };
+    let (slots, errors) = parse_brain(&source);
+    let mut warnings = vec!();
+
+    // Instructions reachable from the first one, following both jumps and fall-throughs
+    let successors = |i: usize| {
+        let (jumps, falls_through) = match slots[i].0 {
+            Some(instruction) => (instruction.jump_targets(), instruction.falls_through()),
+            None => (vec!(), true)
+        };
+        jumps.into_iter().chain(falls_through.then_some(i + 1))
+    };
+    let mut reachable = vec![false; slots.len()];
+    let mut to_visit = if slots.is_empty() { vec!() } else { vec![0] };
+    while let Some(i) = to_visit.pop() {
+        if i >= slots.len() || reachable[i] {
+            continue
+        }
+        reachable[i] = true;
+        to_visit.extend(successors(i));
+    }
+
+    // Unreachable instructions are reported as contiguous blocks
+    let mut i = 0;
+    while i < slots.len() {
+        if reachable[i] {
+            i += 1;
+            continue
+        }
+        let first = i;
+        while i < slots.len() && !reachable[i] {
+            i += 1
+        }
+        warnings.push(BrainWarning::Unreachable { first_line: slots[first].1, last_line: slots[i - 1].1 })
+    }
+
+    // Each loop is reported once, on its first instruction
+    let goto_target = |i: usize| match slots.get(i) {
+        Some((Some(Instruction::Goto(target)), _)) => Some(*target),
+        _ => None
+    };
+    for start in 0..slots.len() {
+        let mut current = start;
+        for _ in 0..slots.len() {
+            match goto_target(current) {
+                Some(next) if next >= start => current = next,
+                _ => break
             }
+            if current == start {
+                warnings.push(BrainWarning::GotoLoop { line: slots[start].1 });
+                break
+            }
+        }
+    }
+
+    (errors, warnings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use crate::simulation::tests::{fixture, BRAIN, BROKEN};
+
+    // The only error found in a brain, which must not load
+    fn single_error(source: &str) -> BrainParseError {
+        let mut errors = parse_instructionset(source).unwrap_err();
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        errors.remove(0)
+    }
+
+    #[test]
+    fn malformed_brains_report_the_faulty_line() {
+        assert!(matches!(
+            single_error("a:\n  Goto b\n"),
+            BrainParseError::UndefinedLabel { line: 2, label } if label == "b"
+        ));
+        assert!(matches!(
+            single_error("a:\n  Mark x\n  Goto a\n"),
+            BrainParseError::NotAnInteger { line: 2, argument } if argument == "x"
+        ));
+        assert!(matches!(
+            single_error("a:\n  Drop\n  Move a\n  Jump a\n"),
+            BrainParseError::UnknownInstruction { line: 4, .. }
+        ));
+        assert!(matches!(
+            single_error("a:\n  Flip 0 a a\n"),
+            BrainParseError::InvalidArgument { line: 2, argument } if argument == "0"
+        ));
+    }
+
+    #[test]
+    fn programs_must_not_run_past_their_end() {
+        assert!(matches!(single_error("a:\n  Move a\n"), BrainParseError::FallsOffEnd { line: 2 }));
+        assert!(matches!(single_error("a:\n  Goto b\n  Sense Here a a Food\nb:\n  Drop\n"), BrainParseError::FallsOffEnd { line: 5 }));
+        assert!(matches!(single_error(""), BrainParseError::EmptyProgram));
+        assert!(matches!(single_error("; nothing yet\nstart:\n"), BrainParseError::EmptyProgram));
+    }
+
+    #[test]
+    fn sixteen_markers_are_parsed() {
+        assert_eq!(parse_instructionset("a:\n  Mark 15\n  Unmark 12\n  Sense Here a a Marker 15\n").unwrap(), vec![
+            Instruction::Mark(15),
+            Instruction::Unmark(12),
+            Instruction::Sense(SenseDirection::Here, 0, 0, Cond::Marker(15))
+        ]);
+    }
+
+    #[test]
+    fn out_of_range_markers_are_refused() {
+        for (source, argument) in [("  Mark 16\n", "16"), ("  Unmark 16\n", "16"), ("  Sense Here a a Marker 17\n", "17")] {
+            assert!(matches!(
+                single_error(&format!("a:\n{}", source)),
+                BrainParseError::InvalidArgument { line: 2, argument: a } if a == argument
+            ));
         }
     }
 
-    instructions
-}
\ No newline at end of file
+    #[test]
+    fn every_dangling_jump_is_reported() {
+        let errors = parse_instructionset("a:\n  Goto end\n  Move a\n  Flip 2 a end\nend:\n").unwrap_err();
+        assert!(matches!(
+            errors.as_slice(),
+            [BrainParseError::JumpOutOfBounds { line: 2, target: 3 }, BrainParseError::JumpOutOfBounds { line: 4, target: 3 }]
+        ), "{:?}", errors);
+    }
+
+    #[test]
+    fn sensing_further_ahead_is_parsed() {
+        assert_eq!(parse_instructionset("a:\n  Sense Ahead2 a b Food\nb:\n  Goto a\n").unwrap(), vec![
+            Instruction::Sense(SenseDirection::AheadN(2), 0, 1, Cond::Food),
+            Instruction::Goto(0)
+        ]);
+        assert!(matches!(
+            single_error("a:\n  Sense Ahead0 a a Food\n"),
+            BrainParseError::InvalidArgument { line: 2, argument } if argument == "Ahead0"
+        ));
+    }
+
+    #[test]
+    fn random_turns_are_parsed() {
+        assert_eq!(parse_instructionset("a:\n  Turn Random\n  Goto a\n").unwrap(), vec![
+            Instruction::Turn(TurnDirection::Random),
+            Instruction::Goto(0)
+        ]);
+    }
+
+    #[test]
+    fn turning_back_is_parsed() {
+        assert_eq!(parse_instructionset("a:\n  Turn Back\n  Goto a\n").unwrap(), vec![
+            Instruction::Turn(TurnDirection::Back),
+            Instruction::Goto(0)
+        ]);
+    }
+
+    #[test]
+    fn disassembly_shows_resolved_jumps() {
+        let source = "search:\n  Sense Ahead found walk Food\nwalk:\n  Move search\n  Goto search\nfound:\n  Move search\n  PickUp search\n  Mark 3\n  Turn Left\n  Drop\n  Flip 4 search walk\n";
+        assert_eq!(disassemble(&parse_instructionset(source).unwrap()), "\
+0000: Sense Ahead -> if 3 else 1 (Food)
+0001: Move -> else 0
+0002: Goto -> 0
+0003: Move -> else 0
+0004: PickUp -> else 0
+0005: Mark 3
+0006: Turn Left
+0007: Drop
+0008: Flip 4 -> if 0 else 1
+");
+    }
+
+    #[test]
+    fn comments_are_ignored() {
+        let source = "; a brain\n# walking around\nstart: ; entry point\n  Move turn # walk\n\n  ; keep going\nturn:\n  Turn Left\n  Goto start ; again\n";
+        assert_eq!(parse_instructionset(source).unwrap(), vec![
+            Instruction::Move(1),
+            Instruction::Turn(TurnDirection::Left),
+            Instruction::Goto(0)
+        ]);
+    }
+
+    #[test]
+    fn adjacent_labels_resolve_to_the_same_instruction() {
+        let source = "a:\n  Drop\nb:\nc:\n  Move b\n  Goto c\nd:\n  Goto a\n";
+        assert_eq!(parse_instructionset(source).unwrap(), vec![
+            Instruction::Drop,
+            Instruction::Move(1),
+            Instruction::Goto(1),
+            Instruction::Goto(0)
+        ]);
+    }
+
+    #[test]
+    fn brain_cache_is_read_while_its_source_is_unchanged() {
+        let source = "a:\n  Move a\n  Goto a\n";
+        let brain = fixture("brain_cache", "a.brain", source);
+        let cache_path = format!("{}.brainc", brain);
+        let _ = fs::remove_file(&cache_path);
+
+        let parsed = load_cached_instructionset(&brain).unwrap();
+        assert!(Path::new(&cache_path).exists());
+        assert_eq!(load_cached_instructionset(&brain).unwrap(), parsed);
+
+        // A cache of some other program shows whether the source was parsed again
+        let planted = vec![Instruction::Drop];
+        let header = (BRAINC_VERSION, source.len() as u64, source_hash(source));
+        let plant = || {
+            let bytes = bincode::serialize(&(header.0, header.1, header.2, &planted)).unwrap();
+            fs::write(&cache_path, bytes).unwrap()
+        };
+        plant();
+        assert_eq!(load_cached_instructionset(&brain).unwrap(), planted);
+
+        // Whatever the timestamps, a source whose contents changed is parsed again
+        plant();
+        fs::write(&brain, "a:\n  Move a\n  Goto a\n\n").unwrap();
+        assert_eq!(load_cached_instructionset(&brain).unwrap(), parsed);
+        // Down to an edit that keeps the length of the source
+        plant();
+        fs::write(&brain, "b:\n  Move b\n  Goto b\n").unwrap();
+        assert_eq!(load_cached_instructionset(&brain).unwrap(), parsed);
+    }
+
+    #[test]
+    fn brains_sharing_a_stem_get_a_cache_each() {
+        let brain = fixture("brain_cache_stems", "a.v1.brain", "a:\n  Move a\n  Goto a\n");
+        let text = fixture("brain_cache_stems", "a.v1.txt", "a:\n  Drop\n  Goto a\n");
+        for path in [&brain, &text] {
+            let _ = fs::remove_file(format!("{}.brainc", path));
+        }
+
+        let (from_brain, from_text) = (load_instructionset(&brain).unwrap(), load_instructionset(&text).unwrap());
+        assert_ne!(from_brain, from_text);
+        for _ in 0..2 {
+            assert_eq!(load_cached_instructionset(&brain).unwrap(), from_brain);
+            assert_eq!(load_cached_instructionset(&text).unwrap(), from_text);
+        }
+    }
+
+    #[test]
+    fn check_reports_unreachable_blocks_and_dangling_labels() {
+        let clean = fixture("check_brain", "clean.brain", BRAIN);
+        let (errors, warnings) = check_brain(&clean);
+        assert!(errors.is_empty() && warnings.is_empty(), "{:?} {:?}", errors, warnings);
+
+        let broken = fixture("check_brain", "broken.brain", BROKEN);
+        let (errors, warnings) = check_brain(&broken);
+        assert!(matches!(
+            errors.as_slice(),
+            [BrainParseError::UndefinedLabel { line: 5, label }] if label == "nowhere"
+        ), "{:?}", errors);
+        assert!(matches!(
+            warnings.as_slice(),
+            [BrainWarning::Unreachable { first_line: 4, last_line: 5 }]
+        ), "{:?}", warnings);
+
+        let looping = fixture("check_brain", "looping.brain", "a:\n  Move b\n  Goto a\nb:\n  Goto c\nc:\n  Goto b\n");
+        let (errors, warnings) = check_brain(&looping);
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert!(matches!(warnings.as_slice(), [BrainWarning::GotoLoop { line: 5 }]), "{:?}", warnings);
+
+        // Brains which would crash the game are errors, not warnings
+        for (name, source) in [("empty.brain", ""), ("falls_off.brain", "a:\n  Move a\n")] {
+            let (errors, warnings) = check_brain(&fixture("check_brain", name, source));
+            assert!(matches!(
+                errors.as_slice(),
+                [BrainParseError::EmptyProgram] | [BrainParseError::FallsOffEnd { line: 2 }]
+            ), "{:?}", errors);
+            assert!(warnings.is_empty(), "{:?}", warnings);
+        }
+    }
+}
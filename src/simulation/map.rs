@@ -1,170 +1,398 @@
 use std::borrow::Borrow;
 use std::cell::RefCell;
-use std::fmt::{Debug, Formatter};
-use super::ant::{Colour, Ant};
+use std::fmt::{Debug, Display, Formatter};
+use super::ant::{Colour, Ant, CardinalDirection};
 
-use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::fs;
+use std::io;
 use std::ops::{Index, IndexMut};
 use std::rc::Rc;
 use crate::simulation::instruction::Cond;
+use crate::simulation::generator::random_world;
 
 
 pub type AntRef = Rc<RefCell<Ant>>;
 
+// Number of distinct markers each colour can set on a cell
+// Brains using a marker past it are refused when parsed
+pub const MARKER_COUNT: usize = 16;
+
+// The cell next to the given one in a given direction, or None if it would
+// lie past the top or left edge of the map
+// This is the only place where the grid's geometry is defined
+// Odd rows are shifted half a cell to the east, so diagonal neighbours
+// depend on the row's parity
+pub fn neighbour(cell: (usize, usize), direction: CardinalDirection) -> Option<(usize, usize)> {
+    let (x, y) = cell;
+    let odd_row = !y.is_multiple_of(2);
+    Some(match direction {
+        CardinalDirection::West => (x.checked_sub(1)?, y),
+        CardinalDirection::East => (x+1, y),
+        CardinalDirection::NorthEast if odd_row => (x+1, y.checked_sub(1)?),
+        CardinalDirection::NorthEast => (x, y.checked_sub(1)?),
+        CardinalDirection::NorthWest if odd_row => (x, y.checked_sub(1)?),
+        CardinalDirection::NorthWest => (x.checked_sub(1)?, y.checked_sub(1)?),
+        CardinalDirection::SouthEast if odd_row => (x+1, y+1),
+        CardinalDirection::SouthEast => (x, y+1),
+        CardinalDirection::SouthWest if odd_row => (x, y+1),
+        CardinalDirection::SouthWest => (x.checked_sub(1)?, y+1)
+    })
+}
+
+// Lines before the first row of a .world file: its name, then its width and height
+const HEADER_LINES: usize = 3;
+// Line starting the list of ants Simulation::to_world_string writes after the rows
+pub const ANTS_SECTION: &str = "ants:";
+
+// Binary .worldb format, see Map::to_worldb
+const WORLDB_MAGIC: [u8; 4] = *b"FAWB";
+const WORLDB_VERSION: u8 = 1;
+const WORLDB_HEADER_SIZE: usize = 13;
+const WORLDB_OBSTACLE: u8 = 0xFF;
+const WORLDB_RED_NEST: u8 = 0xFE;
+const WORLDB_BLACK_NEST: u8 = 0xFD;
+
+// What a cell is made of when a world is loaded
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum Tile {
+    Empty(u32),
+    Obstacle,
+    Nest(Colour)
+}
+
+// Everything that makes a .world or .worldb file unplayable
+// Line numbers start at 1, as in a text editor
+#[derive(Debug)]
+pub enum WorldError {
+    Io(io::Error),
+    InvalidHeader { line: usize },
+    RaggedRow { line: usize, expected: usize, found: usize },
+    InvalidCell { line: usize, found: char },
+    SizeMismatch { header: (usize, usize), found: (usize, usize) },
+    NestCountMismatch { red: usize, black: usize },
+    InvalidBinary { reason: &'static str }
+}
+impl Display for WorldError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "could not read world file: {}", e),
+            Self::InvalidHeader { line } => write!(f, "line {}: size in header is not an integer", line),
+            Self::RaggedRow { line, expected, found } => write!(f, "line {}: row has {} cells instead of {}", line, found, expected),
+            Self::InvalidCell { line, found } => write!(f, "line {}: '{}' is not a cell", line, found),
+            Self::SizeMismatch { header, found } => write!(f, "header declares a {}x{} map but rows make it {}x{}", header.0, header.1, found.0, found.1),
+            Self::NestCountMismatch { red, black } => write!(f, "red has {} nest cells but black has {}", red, black),
+            Self::InvalidBinary { reason } => write!(f, "invalid binary world: {}", reason)
+        }
+    }
+}
+impl std::error::Error for WorldError {}
+impl From<io::Error> for WorldError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
 pub enum Cell {
-    Empty { food: u8, occupant: Option<AntRef>, markers: [u8; 2]},
+    Empty { food: u32, occupant: Option<AntRef>, markers: [u16; 2]},
     Obstacle,
-    Nest { colour: Colour, food: u8, occupant: Option<AntRef>, markers: [u8; 2] }
+    Nest { colour: Colour, food: u32, occupant: Option<AntRef>, markers: [u16; 2] }
+}
+impl Cell {
+    // Units of food lying on the cell, obstacles never having any
+    pub fn food(&self) -> u32 {
+        match self {
+            Self::Empty { food, .. } | Self::Nest { food, .. } => *food,
+            Self::Obstacle => 0
+        }
+    }
 }
 
 // A map contains a matrix of cells, which can be obstacles or empty.
-// Empty cells can have at most 9 units of food on them
+// Worlds put at most 9 units of food on a cell, though ants can pile up more
 pub struct Map {
     cells: Vec<Cell>,
-    size: (usize, usize)
+    size: (usize, usize),
+    // Cells which started with food, along with their initial amount
+    food_sources: Vec<((usize, usize), u32)>,
+    // Units of food lying outside of the nests, kept up to date by every
+    // food-changing method
+    loose_food: u32
 }
 impl Map {
     // Loads a map from a file
     // Returns loaded map, as well as a vector of ants derived from it
-    pub fn load_file(path: &str) -> (Self, Vec<AntRef>) {
-        let mut ants = vec!();
-        let mut map = Self {
-            cells: Vec::new(),
-            size: (0, 0)
-        };
-
-        let mut f = BufReader::new(
-            File::open(path)
-                .expect("could not open file")
-        );
-        let mut buff = Vec::<u8>::new();
+    pub fn load_file(path: &str, max_ants_per_team: Option<usize>) -> Result<(Self, Vec<AntRef>), WorldError> {
+        Self::from_world_string(&fs::read_to_string(path)?, max_ants_per_team)
+    }
 
-        // First read the header
-        f.read_until(b'\n', &mut buff).expect("could not read from file");
-        buff.clear();
+    // Loads a map from the contents of a .world file
+    // Returns loaded map, as well as a vector of ants derived from it
+    // Worlds whose rows do not match the header are refused, as well as those
+    // from_tiles refuses
+    pub fn from_world_string(world: &str, max_ants_per_team: Option<usize>) -> Result<(Self, Vec<AntRef>), WorldError> {
+        // First read the header, its first line being the world's name
+        let mut lines = world.lines();
+        lines.next();
         // x size
-        f.read_until(b'\n', &mut buff).expect("could not read from file");
-        let s = String::from_utf8(buff)
-            .expect("invalid characters in instruction file");
-        map.size.0 = s.trim().parse::<usize>()
-            .expect("Size x in header is not an integer");
-        buff = s.into_bytes();
-        buff.clear();
-
+        let width = lines.next().and_then(|l| l.trim().parse::<usize>().ok())
+            .ok_or(WorldError::InvalidHeader { line: 2 })?;
         // y size
-        f.read_until(b'\n', &mut buff).expect("could not read from file");
-        let s = String::from_utf8(buff)
-            .expect("invalid characters in instruction file");
-        map.size.1 = s.trim().parse::<usize>()
-            .expect("Size y in header is not an integer");
-        buff = s.into_bytes();
-        buff.clear();
-
-        // And now the actual map
-        let mut x = 0;
-        let mut y = 0;
-        let mut id = 0;
-        let mut get_id = || {
-            id += 1;
-            id - 1
+        let height = lines.next().and_then(|l| l.trim().parse::<usize>().ok())
+            .ok_or(WorldError::InvalidHeader { line: 3 })?;
+
+        // The map's rows go on until the end of the file or the ANTS_SECTION, which is
+        // ignored along with everything after it, as are blank lines after the last row
+        let is_cell = |c: char| matches!(c, '#' | '.' | '+' | '-') || c.is_ascii_digit();
+        let mut rows: Vec<Vec<char>> = vec!();
+        for (y, l) in lines.take_while(|l| l.trim() != ANTS_SECTION).enumerate() {
+            let row: Vec<char> = l.chars().filter(|c| !c.is_whitespace()).collect();
+            if let Some(found) = row.iter().find(|c| !is_cell(**c)) {
+                return Err(WorldError::InvalidCell { line: y + HEADER_LINES + 1, found: *found })
+            }
+            rows.push(row)
+        }
+        while rows.last().is_some_and(|row| row.is_empty()) {
+            rows.pop();
+        }
+        let found_width = rows.first().map_or(0, |row| row.len());
+        if let Some((y, row)) = rows.iter().enumerate().find(|(_, row)| row.len() != found_width) {
+            return Err(WorldError::RaggedRow { line: y + HEADER_LINES + 1, expected: found_width, found: row.len() })
+        }
+        if (found_width, rows.len()) != (width, height) {
+            return Err(WorldError::SizeMismatch { header: (width, height), found: (found_width, rows.len()) })
+        }
+        let tiles: Vec<Tile> = rows.iter()
+            .flatten()
+            .map(|c| match c {
+                '#' => Tile::Obstacle,
+                '+' => Tile::Nest(Colour::Red),
+                '-' => Tile::Nest(Colour::Black),
+                // '.' being an empty cell without food
+                _ => Tile::Empty(c.to_digit(10).unwrap_or(0))
+            })
+            .collect();
+        Self::from_tiles((width, height), &tiles, max_ants_per_team)
+    }
+
+    // Loads a map from the contents of a .worldb file, see to_worldb
+    // Returns loaded map, as well as a vector of ants derived from it
+    // The same checks as from_world_string apply
+    pub fn from_worldb(bytes: &[u8], max_ants_per_team: Option<usize>) -> Result<(Self, Vec<AntRef>), WorldError> {
+        let invalid = |reason| WorldError::InvalidBinary { reason };
+        let header = bytes.get(..WORLDB_HEADER_SIZE).ok_or(invalid("truncated header"))?;
+        if header[..4] != WORLDB_MAGIC {
+            return Err(invalid("not a binary world"))
+        }
+        if header[4] != WORLDB_VERSION {
+            return Err(invalid("unsupported version"))
+        }
+        let dimension = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap()) as usize;
+        let size = (dimension(5), dimension(9));
+        let cells = &bytes[WORLDB_HEADER_SIZE..];
+        if cells.len() != size.0 * size.1 {
+            return Err(WorldError::SizeMismatch { header: size, found: (size.0, cells.len() / size.0.max(1)) })
+        }
+        let tiles: Vec<Tile> = cells.iter()
+            .map(|b| match *b {
+                WORLDB_OBSTACLE => Tile::Obstacle,
+                WORLDB_RED_NEST => Tile::Nest(Colour::Red),
+                WORLDB_BLACK_NEST => Tile::Nest(Colour::Black),
+                food => Tile::Empty(food as u32)
+            })
+            .collect();
+        Self::from_tiles(size, &tiles, max_ants_per_team)
+    }
+
+    // Builds a map from its cells in reading order, refusing worlds giving one colour
+    // more nest cells (and thus more ants) than the other
+    // Given a cap, only the first nest cells of red in reading order get an ant, and
+    // the last ones of black, so that in a world mirrored through its centre black's
+    // ants stand on the mirrors of red's. Other nest cells are left empty
+    fn from_tiles(size: (usize, usize), tiles: &[Tile], max_ants_per_team: Option<usize>) -> Result<(Self, Vec<AntRef>), WorldError> {
+        let nests = [Colour::Red, Colour::Black].map(|colour| tiles.iter().filter(|t| **t == Tile::Nest(colour)).count());
+        if nests[0] != nests[1] {
+            return Err(WorldError::NestCountMismatch { red: nests[0], black: nests[1] })
+        }
+
+        let mut ants = vec!();
+        let mut map = Self {
+            cells: Vec::with_capacity(tiles.len()),
+            size,
+            food_sources: Vec::new(),
+            loose_food: 0
         };
-        while f.read_until(b'\0', &mut buff).expect("could not read from file") != 0 {
-            let s = String::from_utf8(buff)
-                .expect("invalid characters in instruction file");
-
-            for c in s.chars() {
-                match c {
-                    '#' => map.cells.push(Cell::Obstacle),
-                    '.' => map.cells.push(Cell::Empty {
-                        food: 0,
-                        occupant: None,
-                        markers: [0; 2]
-                    }),
-                    '+' => {
-                        let new_ant = Ant::new(get_id(), Colour::Red, (x, y));
-                        let ant_ref = Rc::new(RefCell::new(new_ant));
-                        ants.push(Rc::clone(&ant_ref));
-                        map.cells.push(Cell::Nest {
-                            colour: Colour::Red,
-                            food: 0,
-                            occupant: Some(Rc::clone(&ant_ref)),
-                            markers: [0; 2]
-                        });
-                    },
-                    '-' => {
-                        let new_ant = Ant::new(get_id(), Colour::Black, (x, y));
+        // Nest cells of each colour seen so far
+        let mut seen = [0; 2];
+        for (i, tile) in tiles.iter().enumerate() {
+            let position = (i % size.0, i / size.0);
+            match *tile {
+                Tile::Obstacle => map.cells.push(Cell::Obstacle),
+                Tile::Nest(colour) => {
+                    let rank = match colour {
+                        Colour::Red => seen[colour.as_index()],
+                        Colour::Black => nests[colour.as_index()] - 1 - seen[colour.as_index()]
+                    };
+                    seen[colour.as_index()] += 1;
+                    let mut occupant = None;
+                    if max_ants_per_team.is_none_or(|max| rank < max) {
+                        let new_ant = Ant::new(ants.len(), colour, position);
                         let ant_ref = Rc::new(RefCell::new(new_ant));
                         ants.push(Rc::clone(&ant_ref));
-                        map.cells.push(Cell::Nest {
-                            colour: Colour::Black,
-                            food: 0,
-                            occupant: Some(Rc::clone(&ant_ref)),
-                            markers: [0; 2]
-                        });
-                    },
-                    ' ' => (),
-                    '\n' => {
-                        y += 1;
-                        x = 0;
-                    },
-                    _ => {
-                        if c.is_digit(10) {
-                            let food = c.to_digit(10).unwrap() as u8;
-                            map.cells.push(Cell::Empty {
-                                food,
-                                occupant: None,
-                                markers: [0; 2]
-                            });
-                        }
+                        occupant = Some(ant_ref);
                     }
+                    map.cells.push(Cell::Nest {
+                        colour,
+                        food: 0,
+                        occupant,
+                        markers: [0; 2]
+                    });
+                },
+                Tile::Empty(food) => {
+                    if food > 0 {
+                        map.food_sources.push((position, food));
+                        map.loose_food += food;
+                    }
+                    map.cells.push(Cell::Empty {
+                        food,
+                        occupant: None,
+                        markers: [0; 2]
+                    });
                 }
-                if !(c == ' ' || c == '\n') { x += 1 }
             }
-
-            buff = s.into_bytes();
-            buff.clear();
         }
 
+        Ok((map, ants))
+    }
+
+    // Generates a random arena, see generator::random_world
+    pub fn generate_random(width: usize, height: usize, seed: u64, obstacle_density: f64, food_clusters: usize) -> (Self, Vec<AntRef>) {
+        Self::from_world_string(&random_world(width, height, seed, obstacle_density, food_clusters), None)
+            .expect("generated worlds are always valid")
+    }
+
+    // Writes the map back in the .world format
+    // Ants and markers are not part of it, and food is capped to the 9 units a
+    // cell can be declared with
+    pub fn to_world_string(&self) -> String {
+        let mut world = format!("exported\n{}\n{}\n", self.size.0, self.size.1);
+        for (i, c) in self.cells.iter().enumerate() {
+            if i % self.size.0 == 0 {
+                if i != 0 {
+                    world.push('\n')
+                }
+                if !(i / self.size.0).is_multiple_of(2) {
+                    world.push(' ')
+                }
+            } else {
+                world.push(' ')
+            }
+            world.push(match c {
+                Cell::Obstacle => '#',
+                Cell::Nest { colour: Colour::Red, .. } => '+',
+                Cell::Nest { colour: Colour::Black, .. } => '-',
+                Cell::Empty { food: 0, .. } => '.',
+                Cell::Empty { food, .. } => char::from_digit((*food).min(9), 10).unwrap()
+            })
+        }
+        world.push('\n');
+        world
+    }
 
-        (map, ants)
+    // Writes the map in the binary .worldb format: WORLDB_MAGIC, WORLDB_VERSION, the
+    // width and height as little endian u32, then one byte per cell in reading order
+    // Cells are WORLDB_OBSTACLE, WORLDB_RED_NEST, WORLDB_BLACK_NEST, or else an empty
+    // cell holding that many units of food
+    // As with to_world_string, ants, markers and the food held by nests are left out
+    pub fn to_worldb(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(WORLDB_HEADER_SIZE + self.cells.len());
+        bytes.extend_from_slice(&WORLDB_MAGIC);
+        bytes.push(WORLDB_VERSION);
+        bytes.extend_from_slice(&(self.size.0 as u32).to_le_bytes());
+        bytes.extend_from_slice(&(self.size.1 as u32).to_le_bytes());
+        bytes.extend(self.cells.iter().map(|c| match c {
+            Cell::Obstacle => WORLDB_OBSTACLE,
+            Cell::Nest { colour: Colour::Red, .. } => WORLDB_RED_NEST,
+            Cell::Nest { colour: Colour::Black, .. } => WORLDB_BLACK_NEST,
+            Cell::Empty { food, .. } => (*food).min(WORLDB_BLACK_NEST as u32 - 1) as u8
+        }));
+        bytes
     }
 
+    // Markers outside of 0..MARKER_COUNT do not exist and are ignored, so that
+    // instructions built without the parser never overflow the bitset
     pub fn mark_pheromone(&mut self, cell: (usize, usize), i: usize, color: Colour) {
-        if i < 7 {
-            match &mut self[cell] {
-                Cell::Empty { markers, .. } => markers[color.as_index()] |= 1 << i,
-                Cell::Nest { markers, .. } => markers[color.as_index()] |= 1 << i,
-                _ => ()
+        if i < MARKER_COUNT {
+            if let Cell::Empty { markers, .. } | Cell::Nest { markers, .. } = &mut self[cell] {
+                markers[color.as_index()] |= 1 << i
             }
         }
     }
     pub fn unmark_pheromone(&mut self, cell: (usize, usize), i: usize, color: Colour) {
-        if i < 7 {
-            match &mut self[cell] {
-                Cell::Empty { markers, .. } => markers[color.as_index()] &= !(1 << i),
-                Cell::Nest { markers, .. } => markers[color.as_index()] |= !(1 << i),
-                _ => ()
+        if i < MARKER_COUNT {
+            if let Cell::Empty { markers, .. } | Cell::Nest { markers, .. } = &mut self[cell] {
+                markers[color.as_index()] &= !(1 << i)
             }
         }
     }
 
     pub fn pickup_food(&mut self, cell: (usize, usize)) -> bool {
         match &mut self[cell] {
-            Cell::Empty { food, .. } | Cell::Nest { food, .. } if *food > 0 => {
+            Cell::Empty { food, .. } if *food > 0 => {
+                *food -= 1;
+                self.loose_food -= 1;
+                true
+            }
+            Cell::Nest { food, .. } if *food > 0 => {
                 *food -= 1;
                 true
             }
             _ => false
         }
     }
-    pub fn drop_food(&mut self, cell: (usize, usize)) {
+    pub fn drop_food(&mut self, cell: (usize, usize), amount: u32) {
         match &mut self[cell] {
-            Cell::Empty { food, .. } | Cell::Nest { food, .. } => *food += 1,
+            Cell::Empty { food, .. } => {
+                *food += amount;
+                self.loose_food += amount;
+            }
+            Cell::Nest { food, .. } => *food += amount,
             _ => ()
         }
     }
 
+    // Width and height of the map
+    pub fn size(&self) -> (usize, usize) {
+        self.size
+    }
+
+    // Every cell along with its position, in reading order
+    pub fn cells(&self) -> impl Iterator<Item = ((usize, usize), &Cell)> {
+        let width = self.size.0;
+        self.cells.iter()
+            .enumerate()
+            .map(move |(i, cell)| ((i % width, i / width), cell))
+    }
+
+    // Units of food lying anywhere but in a nest
+    pub fn loose_food(&self) -> u32 {
+        self.loose_food
+    }
+
+    // Adds some food back on every cell which started with food, without going
+    // above its initial amount
+    // Nests and obstacles are never food sources
+    pub fn regrow_food(&mut self, amount: u32) {
+        for i in 0..self.food_sources.len() {
+            let (cell, initial_food) = self.food_sources[i];
+            if let Cell::Empty { food, .. } = &mut self[cell] {
+                if *food < initial_food {
+                    let grown = amount.min(initial_food - *food);
+                    *food += grown;
+                    self.loose_food += grown;
+                }
+            }
+        }
+    }
 
     pub fn move_to(&mut self, from: (usize, usize), to: (usize, usize)) -> bool {
         if self.occupied(to) {
@@ -183,17 +411,81 @@ impl Map {
             true
         }
     }
-    fn occupied(&self, cell: (usize, usize)) -> bool {
-        // Checks whether what we want to check is in bounds or not
-        if cell.0 >= self.size.0 || cell.1 >= self.size.1 {
-            return true
+    // Whether nothing can move onto a cell, be it an ant, an obstacle or the edge of the map
+    pub fn occupied(&self, cell: (usize, usize)) -> bool {
+        !self.in_bounds(cell) || self.is_obstacle(cell) || self.occupant_at(cell).is_some()
+    }
+
+    fn in_bounds(&self, cell: (usize, usize)) -> bool {
+        cell.0 < self.size.0 && cell.1 < self.size.1
+    }
+
+    // The ant standing on a cell, if any
+    // Cells outside of the map hold no ant
+    pub fn occupant_at(&self, cell: (usize, usize)) -> Option<&AntRef> {
+        if !self.in_bounds(cell) {
+            return None
         }
         match &self[cell] {
-            Cell::Empty { occupant, .. }
-            | Cell::Nest { occupant, .. } => {
-                occupant.is_some()
-            },
-            _ => true
+            Cell::Empty { occupant, .. } | Cell::Nest { occupant, .. } => occupant.as_ref(),
+            Cell::Obstacle => None
+        }
+    }
+
+    // Whether a cell is an obstacle, cells outside of the map not being any
+    pub fn is_obstacle(&self, cell: (usize, usize)) -> bool {
+        self.in_bounds(cell) && matches!(self[cell], Cell::Obstacle)
+    }
+
+    // The six cells around a given one along with their positions, in
+    // CardinalDirection::iter() order
+    // Neighbours lying outside of the map are None
+    pub fn surroundings(&self, cell: (usize, usize)) -> [Option<(&Cell, (usize, usize))>; 6] {
+        CardinalDirection::ALL.map(|d| {
+            neighbour(cell, d)
+                .filter(|(x, y)| *x < self.size.0 && *y < self.size.1)
+                .map(|position| (&self[position], position))
+        })
+    }
+
+    // Counts the enemy ants (from the given colour's perspective) around a cell,
+    // the outside of the map holding no ant
+    pub fn enemies_around(&self, cell: (usize, usize), colour: Colour) -> usize {
+        self.surroundings(cell)
+            .into_iter()
+            .flatten()
+            .filter(|(_, position)| self.check_condition(Cond::Foe, colour, *position))
+            .count()
+    }
+
+    // Puts an ant on a free cell, returning whether it could be placed
+    pub fn place_occupant(&mut self, cell: (usize, usize), ant: AntRef) -> bool {
+        if self.occupied(cell) {
+            return false
+        }
+        match &mut self[cell] {
+            Cell::Empty { occupant, .. } | Cell::Nest { occupant, .. } => *occupant = Some(ant),
+            _ => ()
+        }
+        true
+    }
+
+    // Takes some food out of a nest, returning whether it held enough
+    pub fn spend_nest_food(&mut self, cell: (usize, usize), amount: u32) -> bool {
+        match &mut self[cell] {
+            Cell::Nest { food, .. } if *food >= amount => {
+                *food -= amount;
+                true
+            }
+            _ => false
+        }
+    }
+
+    // Removes the ant standing on a cell, returning it
+    pub fn remove_occupant(&mut self, cell: (usize, usize)) -> Option<AntRef> {
+        match &mut self[cell] {
+            Cell::Empty { occupant, .. } | Cell::Nest { occupant, .. } => occupant.take(),
+            _ => None
         }
     }
 
@@ -229,7 +521,7 @@ impl Map {
                 Cell::Empty { occupant, .. } | Cell::Nest { occupant, .. } => {
                     if let Some(ant) = occupant {
                         let ant: &RefCell<Ant> = ant.borrow();
-                        ant.borrow().colour == perspective && ant.borrow().has_food
+                        ant.borrow().colour == perspective && ant.borrow().has_food()
                     } else {
                         false
                     }
@@ -240,7 +532,7 @@ impl Map {
                 Cell::Empty { occupant, .. } | Cell::Nest { occupant, .. } => {
                     if let Some(ant) = occupant {
                         let ant: &RefCell<Ant> = ant.borrow();
-                        ant.borrow().colour != perspective && ant.borrow().has_food
+                        ant.borrow().colour != perspective && ant.borrow().has_food()
                     } else {
                         false
                     }
@@ -253,7 +545,9 @@ impl Map {
             }
             Cond::Rock => matches!(self[cell], Cell::Obstacle),
             Cond::Marker(i) => match self[cell] {
-                Cell::Empty { markers, .. } | Cell::Nest { markers, .. } => markers[perspective.as_index()] & (1 << i) != 0,
+                Cell::Empty { markers, .. } | Cell::Nest { markers, .. } => {
+                    i < MARKER_COUNT && markers[perspective.as_index()] & (1 << i) != 0
+                }
                 _ => false
             }
             Cond::FoeMarker => match self[cell] {
@@ -276,8 +570,8 @@ impl Map {
         let (mut red_points, mut black_points) = (0, 0);
         for c in &self.cells {
             match c {
-                Cell::Nest { colour: Colour::Red, food, .. } => red_points += *food as u32,
-                Cell::Nest { colour: Colour::Black, food, .. } => black_points += *food as u32,
+                Cell::Nest { colour: Colour::Red, food, .. } => red_points += *food,
+                Cell::Nest { colour: Colour::Black, food, .. } => black_points += *food,
                 _ => ()
             }
         }
@@ -306,8 +600,8 @@ impl Debug for Map {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         for (i, c) in self.cells.iter().enumerate() {
             if i % self.size.0 == 0 {
-                if ((i / self.size.0) % 2) == 0 {
-                    write!(f, "\n")?
+                if (i / self.size.0).is_multiple_of(2) {
+                    writeln!(f)?
                 } else {
                     write!(f, "\n ")?
                 }
@@ -338,4 +632,167 @@ impl Debug for Map {
         }
         write!(f, "")
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Positions of the ants of a colour, sorted
+    fn positions(ants: &[AntRef], colour: Colour) -> Vec<(usize, usize)> {
+        let mut positions: Vec<(usize, usize)> = ants.iter()
+            .map(|ant| {
+                let a: &RefCell<Ant> = ant.borrow();
+                let a = a.borrow();
+                (a.colour, a.position)
+            })
+            .filter(|(c, _)| *c == colour)
+            .map(|(_, position)| position)
+            .collect();
+        positions.sort();
+        positions
+    }
+
+    // Axial coordinates of a cell, in which every direction adds a constant offset
+    // Odd rows being shifted east, going down a row moves half a cell east on odd rows
+    fn axial((x, y): (usize, usize)) -> (i64, i64) {
+        (x as i64 - (y as i64 - (y as i64 & 1)) / 2, y as i64)
+    }
+
+    #[test]
+    fn corners_have_neighbours_missing() {
+        let (map, _) = Map::from_world_string("open\n4\n3\n+ . . .\n . . . -\n. 3 . .\n", None).unwrap();
+        let positions = |cell| map.surroundings(cell).map(|around| around.map(|(_, position)| position));
+        // East, south-east, south-west, west, north-west and north-east
+        assert_eq!(positions((0, 0)), [Some((1, 0)), Some((0, 1)), None, None, None, None]);
+        assert_eq!(positions((3, 2)), [None, None, None, Some((2, 2)), Some((2, 1)), Some((3, 1))]);
+        assert_eq!(map.enemies_around((0, 0), Colour::Black), 0);
+    }
+
+    #[test]
+    fn walking_in_one_direction_is_a_straight_line() {
+        let start = (10, 10);
+        let path: Vec<(usize, usize)> = std::iter::successors(Some(start), |cell| neighbour(*cell, CardinalDirection::SouthEast))
+            .take(7)
+            .collect();
+        assert_eq!(path, vec![(10, 10), (10, 11), (11, 12), (11, 13), (12, 14), (12, 15), (13, 16)]);
+
+        for direction in CardinalDirection::iter() {
+            let path: Vec<(i64, i64)> = std::iter::successors(Some(start), |cell| neighbour(*cell, direction))
+                .take(7)
+                .map(axial)
+                .collect();
+            let step = (path[1].0 - path[0].0, path[1].1 - path[0].1);
+            assert!(path.windows(2).all(|w| (w[1].0 - w[0].0, w[1].1 - w[0].1) == step), "{:?}: {:?}", direction, path);
+        }
+    }
+
+    #[test]
+    fn mirrored_ants_face_mirrored_directions() {
+        let (map, ants) = Map::generate_random(20, 20, 3, 0.1, 3);
+        let (width, height) = map.size();
+        let ants: Vec<(Colour, (usize, usize), CardinalDirection)> = ants.iter()
+            .map(|ant| {
+                let a: &RefCell<Ant> = ant.borrow();
+                let a = a.borrow();
+                (a.colour, a.position, a.direction())
+            })
+            .collect();
+        assert!(!ants.is_empty());
+        for (colour, (x, y), direction) in &ants {
+            let mirror = (colour.opposite(), (width - 1 - x, height - 1 - y), direction.opposite());
+            assert!(ants.contains(&mirror), "{:?} has no mirror", (colour, (x, y), direction));
+        }
+    }
+
+    #[test]
+    fn capped_ants_are_mirrored() {
+        let (map, ants) = Map::from_world_string(&random_world(20, 20, 3, 0.1, 3), Some(3)).unwrap();
+        let (width, height) = map.size();
+        let red = positions(&ants, Colour::Red);
+        let mut mirrored: Vec<(usize, usize)> = positions(&ants, Colour::Black).iter()
+            .map(|(x, y)| (width - 1 - x, height - 1 - y))
+            .collect();
+        mirrored.sort();
+
+        assert_eq!(red.len(), 3);
+        assert_eq!(mirrored, red);
+    }
+
+    #[test]
+    fn unfair_or_malformed_worlds_are_refused() {
+        let error = |world: &str| Map::from_world_string(world, None).err();
+        assert!(matches!(
+            error("unfair\n4\n1\n+ + . -\n"),
+            Some(WorldError::NestCountMismatch { red: 2, black: 1 })
+        ));
+        assert!(matches!(
+            error("ragged\n3\n2\n+ . .\n . -\n"),
+            Some(WorldError::RaggedRow { line: 5, expected: 3, found: 2 })
+        ));
+        assert!(matches!(
+            error("small\n4\n2\n+ . -\n . . .\n"),
+            Some(WorldError::SizeMismatch { header: (4, 2), found: (3, 2) })
+        ));
+        assert!(matches!(
+            error("typo\n3\n1\n+ x -\n"),
+            Some(WorldError::InvalidCell { line: 4, found: 'x' })
+        ));
+        assert!(matches!(
+            error("gap\n3\n2\n+ . .\n\n . . -\n"),
+            Some(WorldError::RaggedRow { line: 5, expected: 3, found: 0 })
+        ));
+        assert!(error("fair\n3\n2\n+ . .\n . . -\n\n\n").is_none());
+        assert!(error("fair\n3\n2\n+ . .\n . . -\nants:\nRed 0 0 East 0\n").is_none());
+    }
+
+    #[test]
+    fn cells_keep_all_the_food_dropped_on_them() {
+        let (mut map, _) = Map::from_world_string("row\n3\n1\n+ 9 -\n", None).unwrap();
+        for _ in 0..100 {
+            map.drop_food((0, 0), 3);
+            map.drop_food((1, 0), 3);
+        }
+        assert_eq!(map.points(), (300, 0));
+        assert_eq!(map[(1, 0)].food(), 309);
+        assert_eq!(map.loose_food(), 309);
+    }
+
+    #[test]
+    fn out_of_range_markers_are_ignored() {
+        let (mut map, _) = Map::from_world_string("row\n3\n1\n+ . -\n", None).unwrap();
+        map.mark_pheromone((1, 0), 7, Colour::Red);
+        map.mark_pheromone((1, 0), 64, Colour::Red);
+        map.unmark_pheromone((1, 0), 64, Colour::Red);
+
+        assert!(map.check_condition(Cond::Marker(7), Colour::Red, (1, 0)));
+        assert!(!map.check_condition(Cond::Marker(7), Colour::Red, (0, 0)));
+        assert!(!map.check_condition(Cond::Marker(MARKER_COUNT), Colour::Red, (1, 0)));
+        assert!(!map.check_condition(Cond::Marker(64), Colour::Red, (1, 0)));
+    }
+
+    #[test]
+    fn high_markers_are_seen_by_foes() {
+        let (mut map, _) = Map::from_world_string("row\n3\n1\n+ . -\n", None).unwrap();
+        map.mark_pheromone((1, 0), MARKER_COUNT - 1, Colour::Red);
+        assert!(map.check_condition(Cond::Marker(MARKER_COUNT - 1), Colour::Red, (1, 0)));
+        assert!(map.check_condition(Cond::FoeMarker, Colour::Black, (1, 0)));
+        assert!(!map.check_condition(Cond::FoeMarker, Colour::Red, (1, 0)));
+    }
+
+    #[test]
+    fn binary_worlds_load_back() {
+        let (map, ants) = Map::generate_random(21, 15, 7, 0.2, 5);
+        let (reloaded, reloaded_ants) = Map::from_worldb(&map.to_worldb(), None).unwrap();
+        assert_eq!(reloaded.size(), map.size());
+        assert_eq!(reloaded.to_world_string(), map.to_world_string());
+        assert!(map.cells().zip(reloaded.cells()).all(|((_, cell), (_, reloaded))| cell.food() == reloaded.food()));
+        assert_eq!(positions(&reloaded_ants, Colour::Red), positions(&ants, Colour::Red));
+        assert_eq!(positions(&reloaded_ants, Colour::Black), positions(&ants, Colour::Black));
+
+        let bytes = map.to_worldb();
+        assert!(matches!(Map::from_worldb(&bytes[..8], None), Err(WorldError::InvalidBinary { .. })));
+        assert!(matches!(Map::from_worldb(b"open\n4\n3\n+ . . .\n", None), Err(WorldError::InvalidBinary { .. })));
+        assert!(matches!(Map::from_worldb(&bytes[..bytes.len() - 1], None), Err(WorldError::SizeMismatch { .. })));
+    }
+}
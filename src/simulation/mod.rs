@@ -1,54 +1,897 @@
 pub mod ant;
+pub mod generator;
 pub mod map;
 pub mod instruction;
+pub mod replay;
+pub mod rules;
+pub mod stats;
 
 use std::borrow::Borrow;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::time::{Duration, Instant};
 use std::cell::RefCell;
 use std::rc::Rc;
-use map::Map;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde::Serialize;
+use map::{Cell, Map, WorldError, ANTS_SECTION};
 use instruction::InstructionSet;
-use crate::simulation::ant::Ant;
-use crate::simulation::instruction::load_instructionset;
+use crate::simulation::ant::{Ant, CardinalDirection, Colour, TraceStep};
+use crate::simulation::instruction::{load_cached_instructionset, parse_instructionset, BrainParseError};
 use crate::simulation::map::AntRef;
+use crate::simulation::replay::{save_replay, Event, TickFrame};
+use crate::simulation::rules::SimulationRules;
+use crate::simulation::stats::{GameOutcome, TeamStats};
+use crate::simulation::instruction::Cond;
+
+// An ant is killed when at least this many of its neighbours are enemies
+const SURROUNDING_ENEMIES_TO_KILL: usize = 5;
+
+// Everything that prevents a simulation from being created, along with the
+// path of the file at fault
+#[derive(Debug)]
+pub enum SimulationError {
+    Io { path: String, error: io::Error },
+    World { path: String, error: WorldError },
+    Brain { path: String, errors: Vec<BrainParseError> }
+}
+impl Display for SimulationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io { path, error } => write!(f, "could not read {}: {}", path, error),
+            Self::World { path, error } => write!(f, "invalid world {}: {}", path, error),
+            Self::Brain { path, errors } => {
+                write!(f, "invalid brain {}:", path)?;
+                for e in errors {
+                    write!(f, "\n{}", e)?
+                }
+                Ok(())
+            }
+        }
+    }
+}
+impl std::error::Error for SimulationError {}
+
+// How fast a simulation ran through a given number of ticks
+#[derive(Debug, Copy, Clone, Serialize)]
+pub struct BenchResult {
+    pub ticks: usize,
+    pub elapsed: Duration,
+    pub ticks_per_second: f64,
+    // Ants alive at the end of the run
+    pub ant_count: usize
+}
+impl Display for BenchResult {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ticks in {:.3}s ({:.0} ticks/s) with {} ants", self.ticks, self.elapsed.as_secs_f64(), self.ticks_per_second, self.ant_count)
+    }
+}
+
+// Given every instruction executed by a traced ant
+pub type Tracer = dyn FnMut(&TraceStep);
+
+// A world as it was read, either in the text or the binary format
+enum WorldSource {
+    Text(String),
+    Binary(Vec<u8>)
+}
+impl WorldSource {
+    fn load(&self, max_ants_per_team: Option<usize>) -> Result<(Map, Vec<AntRef>), WorldError> {
+        match self {
+            Self::Text(world) => Map::from_world_string(world, max_ants_per_team),
+            Self::Binary(world) => Map::from_worldb(world, max_ants_per_team)
+        }
+    }
+}
 
 // Represents the current state of a simulation
 pub struct Simulation {
     pub ants: Vec<AntRef>,
     pub map: Map,
-    instructions: [InstructionSet; 2]
+    pub rules: SimulationRules,
+    // Contents of the .world or .worldb file, kept to start the game over
+    world: WorldSource,
+    // Both colours share the same program when given the same brain file
+    instructions: [Rc<InstructionSet>; 2],
+    rng: StdRng,
+    // Id given to the next ant spawned during the game
+    next_ant_id: usize,
+    tick: usize,
+    stalled_for: usize,
+    stats: TeamStats,
+    recording: Option<Vec<TickFrame>>,
+    // Id of the traced ant, and what to do with each instruction it executes
+    tracer: Option<(usize, Box<Tracer>)>
 }
 impl Simulation {
-    pub fn new(map_path: &str, red_brain_path: &str, black_brain_path: &str) -> Self {
-        let (map, ants) = Map::load_file(map_path);
-        Self {
+    // Giving a seed makes the simulation fully reproducible, otherwise
+    // the rng is seeded from the system's entropy
+    pub fn new(map_path: &str, red_brain_path: &str, black_brain_path: &str, seed: Option<u64>) -> Result<Self, SimulationError> {
+        Self::with_rules(map_path, red_brain_path, black_brain_path, seed, SimulationRules::default())
+    }
+
+    // Same as new, with rules decided before the game starts as some of them,
+    // such as max_ants_per_team, are applied when loading the world
+    // Worlds ending in .worldb are read in the binary format, see load_worldb
+    pub fn with_rules(map_path: &str, red_brain_path: &str, black_brain_path: &str, seed: Option<u64>, rules: SimulationRules) -> Result<Self, SimulationError> {
+        if map_path.ends_with(".worldb") {
+            return Self::load_worldb(map_path, red_brain_path, black_brain_path, seed, rules)
+        }
+        let world = fs::read_to_string(map_path)
+            .map_err(|error| SimulationError::Io { path: String::from(map_path), error })?;
+        Self::with_world(WorldSource::Text(world), map_path, red_brain_path, black_brain_path, seed, rules)
+    }
+
+    // Same as with_rules, the world being in the binary .worldb format whatever its
+    // extension, see Map::to_worldb
+    // A .worldb file and the .world file it was saved from give the same game
+    pub fn load_worldb(map_path: &str, red_brain_path: &str, black_brain_path: &str, seed: Option<u64>, rules: SimulationRules) -> Result<Self, SimulationError> {
+        let world = fs::read(map_path)
+            .map_err(|error| SimulationError::Io { path: String::from(map_path), error })?;
+        Self::with_world(WorldSource::Binary(world), map_path, red_brain_path, black_brain_path, seed, rules)
+    }
+
+    fn with_world(world: WorldSource, map_path: &str, red_brain_path: &str, black_brain_path: &str, seed: Option<u64>, rules: SimulationRules) -> Result<Self, SimulationError> {
+        let red_instructions = Rc::new(Self::load_brain(red_brain_path)?);
+        let black_instructions = if black_brain_path == red_brain_path {
+            Rc::clone(&red_instructions)
+        } else {
+            Rc::new(Self::load_brain(black_brain_path)?)
+        };
+        Self::build(world, map_path, [red_instructions, black_instructions], seed, rules)
+    }
+
+    // Same as with_rules, given the contents of the .world and .brain files rather
+    // than their paths
+    // Errors name the faulty source <world>, <red brain> or <black brain> instead of a path
+    pub fn from_sources(world: &str, red_brain: &str, black_brain: &str, seed: Option<u64>, rules: SimulationRules) -> Result<Self, SimulationError> {
+        let parse = |source: &str, name: &str| parse_instructionset(source)
+            .map(Rc::new)
+            .map_err(|errors| SimulationError::Brain { path: String::from(name), errors });
+        let instructions = [parse(red_brain, "<red brain>")?, parse(black_brain, "<black brain>")?];
+        Self::build(WorldSource::Text(String::from(world)), "<world>", instructions, seed, rules)
+    }
+
+    fn build(world: WorldSource, world_path: &str, instructions: [Rc<InstructionSet>; 2], seed: Option<u64>, rules: SimulationRules) -> Result<Self, SimulationError> {
+        let (map, ants) = world.load(rules.max_ants_per_team)
+            .map_err(|error| SimulationError::World { path: String::from(world_path), error })?;
+        Ok(Self {
+            next_ant_id: ants.len(),
             ants,
             map,
-            instructions: [
-                load_instructionset(red_brain_path),
-                load_instructionset(black_brain_path)
-            ]
+            rules,
+            world,
+            instructions,
+            rng: seed.map_or_else(StdRng::from_entropy, StdRng::seed_from_u64),
+            tick: 0,
+            stalled_for: 0,
+            stats: TeamStats::default(),
+            recording: None,
+            tracer: None
+        })
+    }
+
+    // Starts the game over from the world it was created with, without reading
+    // any file again
+    // Food, markers and ants are back to their initial state and stats are cleared,
+    // as is the recording if any. The rng keeps going so that the next game differs
+    pub fn reset(&mut self) {
+        let (map, ants) = self.world.load(self.rules.max_ants_per_team)
+            .expect("the world was valid when the simulation was created");
+        self.map = map;
+        self.next_ant_id = ants.len();
+        self.ants = ants;
+        self.tick = 0;
+        self.stalled_for = 0;
+        self.stats = TeamStats::default();
+        if let Some(recording) = &mut self.recording {
+            recording.clear()
+        }
+    }
+
+    // From now on, tracer is given every instruction executed by the ant with the given
+    // id, replacing the previously traced ant if any
+    // Nothing is traced once the ant is dead, nor while it is on cooldown
+    pub fn trace_ant(&mut self, ant: usize, tracer: impl FnMut(&TraceStep) + 'static) {
+        self.tracer = Some((ant, Box::new(tracer)))
+    }
+
+    pub fn stop_tracing(&mut self) {
+        self.tracer = None
+    }
+
+    // From now on, every tick's changes are kept so that the game can be replayed
+    pub fn enable_recording(&mut self) {
+        if self.recording.is_none() {
+            self.recording = Some(vec!())
         }
     }
 
+    // Frames recorded since recording was enabled, one per tick
+    pub fn replay(&self) -> &[TickFrame] {
+        self.recording.as_deref().unwrap_or_default()
+    }
+
+    pub fn save_replay(&self, path: &str) -> io::Result<()> {
+        save_replay(self.replay(), path)
+    }
+
+    // Saves the world the simulation was created with in the binary .worldb format,
+    // whichever format it was read from
+    pub fn save_worldb(&self, path: &str) -> io::Result<()> {
+        let (map, _) = self.world.load(self.rules.max_ants_per_team)
+            .expect("the world was valid when the simulation was created");
+        fs::write(path, map.to_worldb())
+    }
+
+    fn load_brain(path: &str) -> Result<InstructionSet, SimulationError> {
+        let path = String::from(path);
+        load_cached_instructionset(&path).map_err(|errors| match <[BrainParseError; 1]>::try_from(errors) {
+            // A brain which could not be read has no other error
+            Ok([BrainParseError::Io(error)]) => SimulationError::Io { path, error },
+            Ok(errors) => SimulationError::Brain { path, errors: errors.into() },
+            Err(errors) => SimulationError::Brain { path, errors }
+        })
+    }
+
     // Each ant executes its current instruction, then
     // surrounded ants are killed
     pub fn process_tick(&mut self) {
+        let mut frame = TickFrame { tick: self.tick, events: vec!() };
+
         // Each ant moves
         for ant in &mut self.ants {
             let ant = Rc::clone(ant);
-            let instruction_set = {
+            let (colour, on_cooldown, traced_instruction) = {
                 let a: &RefCell<Ant> = ant.borrow();
-                &self.instructions[a.borrow().colour.as_index()]
+                let a = a.borrow();
+                let traced = self.tracer.as_ref().is_some_and(|(id, _)| *id == a.id);
+                (a.colour, a.on_cooldown(), (traced && !a.on_cooldown()).then(|| a.current_instruction()))
             };
-            Ant::process_tick(ant, &mut self.map, instruction_set)
+            let instruction_set = &self.instructions[colour.as_index()];
+            let event = Ant::process_tick(Rc::clone(&ant), &mut self.map, instruction_set, &mut self.rng, &self.rules);
+            if let (Some(instruction_index), Some((_, tracer))) = (traced_instruction, &mut self.tracer) {
+                let a: &RefCell<Ant> = ant.borrow();
+                let a = a.borrow();
+                tracer(&TraceStep {
+                    tick: self.tick,
+                    ant: a.id,
+                    instruction_index,
+                    instruction: instruction_set[instruction_index],
+                    position: a.position,
+                    direction: a.direction(),
+                    food: a.food,
+                    next_instruction: a.current_instruction()
+                })
+            }
+
+            let stats = self.stats.get_mut(colour);
+            if on_cooldown {
+                stats.cooldown_ticks += 1
+            }
+            match event {
+                Some(Event::Moved { .. }) => stats.moves += 1,
+                Some(Event::PickedUp { .. }) => stats.food_picked_up += 1,
+                Some(Event::Dropped { cell, amount, .. }) if self.map.check_condition(Cond::Home, colour, cell) => {
+                    stats.food_delivered += amount
+                }
+                _ => ()
+            }
+            if let (Some(event), Some(_)) = (event, &self.recording) {
+                frame.events.push(event)
+            }
         }
 
         // Surrounded ants are killed
-        // TODO
+        // They are all found before any is removed so that the order in which ants are
+        // checked does not matter. A death only lowers its neighbours' enemy counts,
+        // so no other ant can become surrounded because of it
+        // Nothing is allocated unless some ant dies
+        let map = &self.map;
+        let killed: Vec<AntRef> = self.ants
+            .extract_if(.., |ant| {
+                let a: &RefCell<Ant> = (*ant).borrow();
+                let a = a.borrow();
+                map.enemies_around(a.position, a.colour) >= SURROUNDING_ENEMIES_TO_KILL
+            })
+            .collect();
+        for ant in killed {
+            let a: &RefCell<Ant> = ant.borrow();
+            let a = a.borrow();
+            self.bury(&a);
+            self.stats.get_mut(a.colour.opposite()).kills += 1;
+            if self.recording.is_some() {
+                frame.events.push(Event::Killed { ant: a.id, cell: a.position })
+            }
+        }
+
+        // Ants which lived for as long as the rules allow die of old age
+        if let Some(lifespan) = self.rules.ant_lifespan {
+            let expired: Vec<AntRef> = self.ants
+                .extract_if(.., |ant| {
+                    let a: &RefCell<Ant> = (*ant).borrow();
+                    a.borrow().age() >= lifespan
+                })
+                .collect();
+            for ant in expired {
+                let a: &RefCell<Ant> = ant.borrow();
+                let a = a.borrow();
+                self.bury(&a);
+                if self.recording.is_some() {
+                    frame.events.push(Event::Died { ant: a.id, cell: a.position })
+                }
+            }
+        }
+
+        // Food grows back every few ticks
+        if let Some(regrowth) = self.rules.regrowth {
+            if regrowth.interval > 0 && (self.tick + 1).is_multiple_of(regrowth.interval) {
+                self.map.regrow_food(regrowth.amount)
+            }
+        }
+
+        // Nests turn the food they hold into new ants, placed on the nest cell or,
+        // if it is taken, on the first free cell around it
+        // A team having max_ants_per_team living ants stops spawning, its nests
+        // keeping their food until some of its ants die
+        if let Some(cost) = self.rules.spawn_cost.filter(|cost| *cost > 0) {
+            let spawning: Vec<((usize, usize), Colour)> = self.map.cells()
+                .filter_map(|(position, cell)| match cell {
+                    Cell::Nest { colour, food, .. } if *food >= cost => Some((position, *colour)),
+                    _ => None
+                })
+                .collect();
+            let mut living = [0; 2];
+            for ant in &self.ants {
+                let a: &RefCell<Ant> = ant.borrow();
+                living[a.borrow().colour.as_index()] += 1;
+            }
+            for (nest, colour) in spawning {
+                if self.rules.max_ants_per_team.is_some_and(|max| living[colour.as_index()] >= max) {
+                    continue
+                }
+                let free_cell = std::iter::once(nest)
+                    .chain(self.map.surroundings(nest).into_iter().flatten().map(|(_, position)| position))
+                    .find(|cell| !self.map.occupied(*cell));
+                if let Some(cell) = free_cell {
+                    self.map.spend_nest_food(nest, cost);
+                    let ant = Rc::new(RefCell::new(Ant::new(self.next_ant_id, colour, cell)));
+                    self.map.place_occupant(cell, Rc::clone(&ant));
+                    self.ants.push(ant);
+                    living[colour.as_index()] += 1;
+                    if self.recording.is_some() {
+                        frame.events.push(Event::Spawned { ant: self.next_ant_id, colour, cell })
+                    }
+                    self.next_ant_id += 1;
+                }
+            }
+        }
+
+        if self.is_stalled() {
+            self.stalled_for += 1
+        } else {
+            self.stalled_for = 0
+        }
+
+        if let Some(recording) = &mut self.recording {
+            recording.push(frame)
+        }
+        self.tick += 1;
+    }
+
+    // Processes a given number of ticks, calling on_tick after each one with the
+    // index of the tick just processed and read access to the simulation
+    pub fn run_for(&mut self, ticks: usize, mut on_tick: impl FnMut(usize, &Simulation)) {
+        for _ in 0..ticks {
+            self.process_tick();
+            on_tick(self.tick - 1, self)
+        }
+    }
+
+    // A snapshot of every living ant as (id, position, direction, colour), by id
+    pub fn ants(&self) -> Vec<(usize, (usize, usize), CardinalDirection, Colour)> {
+        self.ants.iter()
+            .map(|ant| {
+                let a: &RefCell<Ant> = ant.borrow();
+                let a = a.borrow();
+                (a.id, a.position, a.direction(), a.colour)
+            })
+            .collect()
+    }
+
+    // Every cell of the map as (position, cell, units of food on it), in reading order
+    pub fn cells(&self) -> impl Iterator<Item = ((usize, usize), &Cell, u32)> {
+        self.map.cells().map(|(position, cell)| (position, cell, cell.food()))
+    }
+
+    // Whether both colours run the same program, be it from the same file or not
+    pub fn same_brains(&self) -> bool {
+        let [red, black] = &self.instructions;
+        Rc::ptr_eq(red, black) || red == black
+    }
+
+    // Processes exactly the given number of ticks, even past the end of the game,
+    // timing how long it takes
+    pub fn benchmark(&mut self, ticks: usize) -> BenchResult {
+        let start = Instant::now();
+        for _ in 0..ticks {
+            self.process_tick()
+        }
+        let elapsed = start.elapsed();
+        BenchResult {
+            ticks,
+            elapsed,
+            ticks_per_second: ticks as f64 / elapsed.as_secs_f64().max(f64::MIN_POSITIVE),
+            ant_count: self.ants.len()
+        }
+    }
+
+    // Id of the ant standing on a cell, if any
+    pub fn occupant_at(&self, cell: (usize, usize)) -> Option<usize> {
+        self.map.occupant_at(cell).map(|ant| {
+            let a: &RefCell<Ant> = ant.borrow();
+            a.borrow().id
+        })
+    }
+
+    pub fn is_obstacle(&self, cell: (usize, usize)) -> bool {
+        self.map.is_obstacle(cell)
+    }
+
+    // Number of ticks processed so far
+    pub fn tick(&self) -> usize {
+        self.tick
+    }
+
+    // Takes a dead ant off the map, whatever it carried being left on its cell
+    fn bury(&mut self, ant: &Ant) {
+        self.map.remove_occupant(ant.position);
+        if ant.has_food() {
+            self.map.drop_food(ant.position, ant.food)
+        }
+    }
+
+    // Whether no food lies outside of the nests and no ant carries any
+    // Scores can then only change if an ant takes food back out of a nest
+    // Food growing back means the game can always go on
+    fn is_stalled(&self) -> bool {
+        self.rules.regrowth.is_none()
+            && self.map.loose_food() == 0
+            && self.ants.iter().all(|ant| {
+                let a: &RefCell<Ant> = ant.borrow();
+                !a.borrow().has_food()
+            })
+    }
+
+    // Whether the game has been stalled for long enough to be considered over
+    pub fn is_finished(&self) -> bool {
+        self.stalled_for >= self.rules.stalemate_ticks
+    }
+
+    // Exports the current state in the .world format, followed by an ANTS_SECTION
+    // listing every ant as "<colour> <x> <y> <direction> <food carried>"
+    // Loading the result back only restores the terrain
+    pub fn to_world_string(&self) -> String {
+        let mut world = self.map.to_world_string();
+        world.push_str(ANTS_SECTION);
+        world.push('\n');
+        for ant in &self.ants {
+            let a: &RefCell<Ant> = ant.borrow();
+            let a = a.borrow();
+            world.push_str(&format!(
+                "{:?} {} {} {:?} {}\n",
+                a.colour, a.position.0, a.position.1, a.direction(), a.food
+            ));
+        }
+        world
+    }
+
+    // What each team did since the start of the game
+    pub fn stats(&self) -> TeamStats {
+        self.stats
     }
 
     // Returns the current food units in each nest
     pub fn points(&self) -> (u32, u32) {
         self.map.points()
     }
-}
\ No newline at end of file
+
+    // Who is currently ahead, surviving ants breaking ties on food
+    pub fn outcome(&self) -> GameOutcome {
+        let red_ants = self.ants.iter()
+            .filter(|ant| {
+                let a: &RefCell<Ant> = (*ant).borrow();
+                a.borrow().colour == Colour::Red
+            })
+            .count();
+        GameOutcome::new(self.points(), (red_ants, self.ants.len() - red_ants))
+    }
+}
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::simulation::rules::RegrowthConfig;
+    use crate::simulation::stats::Stats;
+
+    // Worlds and brains shared with the CLI tests
+    pub(crate) const WORLD: &str = include_str!("../../tests/fixtures/open.world");
+    pub(crate) const BRAIN: &str = include_str!("../../tests/fixtures/wanderer.brain");
+    pub(crate) const IDLE: &str = include_str!("../../tests/fixtures/idle.brain");
+    pub(crate) const TINY: &str = include_str!("../../tests/fixtures/tiny.world");
+    // Wanders randomly until it finds food, then brings it back to a nest
+    pub(crate) const FORAGER: &str = include_str!("../../tests/fixtures/forager.brain");
+    pub(crate) const BROKEN: &str = include_str!("../../tests/fixtures/broken.brain");
+
+    // Writes a file under a directory of the system's temporary one named after the test
+    pub(crate) fn fixture(test: &str, name: &str, contents: &str) -> String {
+        let directory = std::env::temp_dir().join(format!("fourmisse-arena-{}", test));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join(name);
+        fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    // Ants of each colour alive in the simulation
+    fn ant_counts(simulation: &Simulation) -> (usize, usize) {
+        let red = simulation.ants().iter().filter(|(.., colour)| *colour == Colour::Red).count();
+        (red, simulation.ants.len() - red)
+    }
+
+    // What each cell is made of, in reading order, along with the food lying on it
+    // Ants, markers and the food held by nests are left out, as in the .world format
+    fn terrain(simulation: &Simulation) -> Vec<(char, u32)> {
+        simulation.cells()
+            .map(|(_, cell, food)| match cell {
+                Cell::Nest { colour: Colour::Red, .. } => ('+', 0),
+                Cell::Nest { colour: Colour::Black, .. } => ('-', 0),
+                Cell::Obstacle => ('#', 0),
+                Cell::Empty { .. } => ('.', food)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn unfair_worlds_do_not_make_a_simulation() {
+        let result = Simulation::from_sources("unfair\n4\n1\n+ + . -\n", IDLE, IDLE, None, SimulationRules::default());
+        assert!(matches!(result, Err(SimulationError::World { error: WorldError::NestCountMismatch { red: 2, black: 1 }, .. })));
+    }
+
+    #[test]
+    fn missing_files_are_reported_with_their_path() {
+        let world = fixture("missing_files", "open.world", WORLD);
+        let brain = fixture("missing_files", "idle.brain", IDLE);
+        let missing = std::env::temp_dir().join("fourmisse-arena-missing_files").join("missing");
+        let missing = missing.to_string_lossy();
+
+        let result = Simulation::new(&missing, &brain, &brain, None);
+        assert!(matches!(result, Err(SimulationError::Io { path, error }) if path == missing && error.kind() == io::ErrorKind::NotFound));
+        let result = Simulation::new(&world, &brain, &missing, None);
+        assert!(matches!(result, Err(SimulationError::Io { path, error }) if path == missing && error.kind() == io::ErrorKind::NotFound));
+    }
+
+    #[test]
+    fn run_for_calls_back_after_every_tick() {
+        let mut simulation = Simulation::from_sources(TINY, FORAGER, FORAGER, Some(3), SimulationRules::default()).unwrap();
+        let mut scores = vec!();
+        simulation.run_for(10, |tick, simulation| scores.push((tick, simulation.tick(), simulation.points())));
+        assert_eq!(scores.len(), 10);
+        assert!(scores.iter().enumerate().all(|(i, (tick, processed, _))| *tick == i && *processed == i + 1));
+    }
+
+    #[test]
+    fn accessors_reflect_the_world() {
+        let simulation = Simulation::from_sources(WORLD, IDLE, IDLE, None, SimulationRules::default()).unwrap();
+        assert_eq!(simulation.ants(), vec![
+            (0, (0, 0), CardinalDirection::East, Colour::Red),
+            (1, (3, 1), CardinalDirection::West, Colour::Black)
+        ]);
+
+        let cells: Vec<((usize, usize), &Cell, u32)> = simulation.cells().collect();
+        assert_eq!(cells.len(), 12);
+        assert_eq!(cells[4].0, (0, 1));
+        assert!(matches!(cells[0].1, Cell::Nest { colour: Colour::Red, .. }));
+        assert!(matches!(cells[7].1, Cell::Nest { colour: Colour::Black, .. }));
+        assert_eq!(cells.iter().map(|(.., food)| food).sum::<u32>(), 3);
+        assert_eq!(cells[9].2, 3);
+    }
+
+    #[test]
+    fn reset_starts_over_without_reading_files() {
+        let world = fixture("reset", "tiny.world", TINY);
+        let brain = fixture("reset", "forager.brain", FORAGER);
+        let mut simulation = Simulation::new(&world, &brain, &brain, Some(5)).unwrap();
+        let initial = terrain(&simulation);
+        let instructions = Rc::clone(&simulation.instructions[0]);
+        simulation.run_for(3000, |_, _| ());
+        assert!(simulation.points() != (0, 0));
+
+        fs::remove_dir_all(std::path::Path::new(&world).parent().unwrap()).unwrap();
+        simulation.reset();
+        assert_eq!(simulation.points(), (0, 0));
+        assert_eq!(simulation.tick(), 0);
+        assert_eq!(simulation.stats(), TeamStats::default());
+        assert_eq!(terrain(&simulation), initial);
+        assert_eq!(ant_counts(&simulation), (4, 4));
+        assert!(Rc::ptr_eq(&simulation.instructions[0], &instructions));
+
+        simulation.run_for(3000, |_, _| ());
+        assert!(simulation.points() != (0, 0));
+    }
+
+    #[test]
+    fn sources_are_named_in_errors() {
+        let simulation = Simulation::from_sources(WORLD, BRAIN, IDLE, Some(0), SimulationRules::default()).unwrap();
+        assert_eq!(ant_counts(&simulation), (1, 1));
+
+        let result = Simulation::from_sources(WORLD, IDLE, "a:\n  Goto b\n", None, SimulationRules::default());
+        assert!(matches!(result, Err(SimulationError::Brain { path, .. }) if path == "<black brain>"));
+        let result = Simulation::from_sources("open\n4\n3\n", IDLE, IDLE, None, SimulationRules::default());
+        assert!(matches!(result, Err(SimulationError::World { path, .. }) if path == "<world>"));
+    }
+
+    #[test]
+    fn benchmark_runs_the_requested_ticks() {
+        let mut simulation = Simulation::from_sources(TINY, FORAGER, FORAGER, Some(1), SimulationRules::default()).unwrap();
+        let result = simulation.benchmark(500);
+        assert_eq!(result.ticks, 500);
+        assert_eq!(simulation.tick(), 500);
+        assert!(result.ticks_per_second > 0.0);
+        assert_eq!(result.ant_count, 8);
+    }
+
+    #[test]
+    fn occupants_and_obstacles_are_told_apart() {
+        let simulation = Simulation::from_sources("row\n4\n1\n+ # . -\n", IDLE, IDLE, None, SimulationRules::default()).unwrap();
+        assert_eq!((simulation.occupant_at((0, 0)), simulation.is_obstacle((0, 0))), (Some(0), false));
+        assert_eq!((simulation.occupant_at((3, 0)), simulation.is_obstacle((3, 0))), (Some(1), false));
+        assert_eq!((simulation.occupant_at((1, 0)), simulation.is_obstacle((1, 0))), (None, true));
+        assert_eq!((simulation.occupant_at((2, 0)), simulation.is_obstacle((2, 0))), (None, false));
+    }
+
+    #[test]
+    fn old_ants_die_leaving_their_food_behind() {
+        let rules = SimulationRules { ant_lifespan: Some(5), move_cooldown: 0, ..Default::default() };
+        let carrier = "a:\n  Move a\n  PickUp a\nb:\n  Goto b\n";
+        let mut simulation = Simulation::from_sources("row\n4\n1\n+ 2 . -\n", carrier, IDLE, None, rules).unwrap();
+        simulation.enable_recording();
+
+        simulation.run_for(4, |_, _| ());
+        assert_eq!(ant_counts(&simulation), (1, 1));
+        assert_eq!(simulation.map[(1, 0)].food(), 1);
+        simulation.process_tick();
+        assert_eq!(ant_counts(&simulation), (0, 0));
+        assert_eq!(simulation.replay()[4].events, vec![
+            Event::Died { ant: 0, cell: (1, 0) },
+            Event::Died { ant: 1, cell: (3, 0) }
+        ]);
+        assert_eq!(simulation.map[(1, 0)].food(), 2);
+        assert_eq!(simulation.map.loose_food(), 2);
+    }
+
+    #[test]
+    fn exported_world_loads_back() {
+        let mut simulation = Simulation::from_sources(TINY, FORAGER, FORAGER, Some(5), SimulationRules::default()).unwrap();
+        simulation.run_for(2000, |_, _| ());
+        assert!(simulation.points() != (0, 0));
+
+        let reloaded = Simulation::from_sources(&simulation.to_world_string(), IDLE, IDLE, None, SimulationRules::default()).unwrap();
+        assert_eq!(reloaded.map.size(), simulation.map.size());
+        assert_eq!(terrain(&reloaded), terrain(&simulation));
+    }
+
+    #[test]
+    fn binary_worlds_play_the_same_game() {
+        let world = fixture("binary_worlds", "tiny.world", TINY);
+        let brain = fixture("binary_worlds", "forager.brain", FORAGER);
+        let binary = world.replace(".world", ".worldb");
+        let mut text = Simulation::new(&world, &brain, &brain, Some(9)).unwrap();
+        text.save_worldb(&binary).unwrap();
+        let mut binary = Simulation::new(&binary, &brain, &brain, Some(9)).unwrap();
+        assert_eq!(terrain(&binary), terrain(&text));
+
+        text.run_for(2000, |_, _| ());
+        binary.run_for(2000, |_, _| ());
+        assert!(text.points() != (0, 0));
+        assert_eq!(binary.to_world_string(), text.to_world_string());
+    }
+
+    #[test]
+    fn replay_records_every_tick() {
+        let mut simulation = Simulation::from_sources(WORLD, BRAIN, IDLE, Some(0), SimulationRules::default()).unwrap();
+        simulation.enable_recording();
+        simulation.run_for(10, |_, _| ());
+
+        let replay = simulation.replay();
+        assert_eq!(replay.len(), 10);
+        assert!(replay.iter().enumerate().all(|(i, frame)| frame.tick == i));
+        assert_eq!(replay[0].events, vec![Event::Moved { ant: 0, from: (0, 0), to: (1, 0) }]);
+    }
+
+    #[test]
+    fn same_seed_plays_the_same_game() {
+        let play = |seed| {
+            let mut simulation = Simulation::from_sources(TINY, FORAGER, FORAGER, Some(seed), SimulationRules::default()).unwrap();
+            simulation.run_for(3000, |_, _| ());
+            (simulation.points(), simulation.to_world_string())
+        };
+        let game = play(7);
+        assert!(game.0 != (0, 0));
+        assert_eq!(play(7), game);
+    }
+
+    // An empty 8x8 world with nests of each colour on the given cells
+    fn world_with(red: &[(usize, usize)], black: &[(usize, usize)]) -> String {
+        let mut world = String::from("nests\n8\n8\n");
+        for y in 0..8 {
+            let row: Vec<&str> = (0..8)
+                .map(|x| match (x, y) {
+                    cell if red.contains(&cell) => "+",
+                    cell if black.contains(&cell) => "-",
+                    _ => "."
+                })
+                .collect();
+            world.push_str(&format!("{}{}\n", if y % 2 == 1 { " " } else { "" }, row.join(" ")));
+        }
+        world
+    }
+
+    #[test]
+    fn surrounded_ants_are_killed() {
+        // (3, 3) has six enemies around it and (3, 5) five, the other ants filling in nests
+        let red = [(3, 3), (3, 5), (1, 1), (0, 7), (1, 7), (2, 7), (3, 7), (4, 7), (5, 7), (6, 7)];
+        let black = [(4, 3), (2, 3), (4, 2), (3, 2), (4, 4), (3, 4), (2, 5), (4, 5), (4, 6), (0, 0)];
+        let mut simulation = Simulation::from_sources(&world_with(&red, &black), IDLE, IDLE, None, SimulationRules::default()).unwrap();
+        simulation.process_tick();
+
+        assert_eq!(simulation.occupant_at((3, 3)), None);
+        assert_eq!(simulation.occupant_at((3, 5)), None);
+        assert_eq!(ant_counts(&simulation), (8, 10));
+        assert_eq!(simulation.stats().black.kills, 2);
+
+        // Four enemies are not enough
+        let red = [(3, 3), (0, 7), (1, 7), (2, 7)];
+        let black = [(4, 3), (2, 3), (4, 2), (3, 2)];
+        let mut simulation = Simulation::from_sources(&world_with(&red, &black), IDLE, IDLE, None, SimulationRules::default()).unwrap();
+        simulation.process_tick();
+        assert_eq!(ant_counts(&simulation), (4, 4));
+    }
+
+    #[test]
+    fn games_without_food_left_to_bring_end_early() {
+        let rules = SimulationRules { stalemate_ticks: 10, ..Default::default() };
+        let mut simulation = Simulation::from_sources(WORLD, FORAGER, FORAGER, Some(0), rules).unwrap();
+        for _ in 0..3 {
+            simulation.map.pickup_food((1, 2));
+        }
+        simulation.map.drop_food((0, 0), 3);
+
+        simulation.run_for(9, |_, _| ());
+        assert!(!simulation.is_finished());
+        simulation.process_tick();
+        assert!(simulation.is_finished());
+
+        let mut simulation = Simulation::from_sources(WORLD, IDLE, IDLE, None, rules).unwrap();
+        for _ in 0..3 {
+            simulation.map.pickup_food((1, 2));
+        }
+        simulation.map.drop_food((3, 1), 3);
+        let result = crate::play(simulation, Some(100_000), None);
+        assert_eq!(result.ticks, 10);
+        assert_eq!(result.outcome.points(), (0, 3));
+
+        // Food left on the ground keeps the game going
+        let simulation = Simulation::from_sources(WORLD, IDLE, IDLE, None, rules).unwrap();
+        assert_eq!(crate::play(simulation, Some(200), None).ticks, 200);
+    }
+
+    #[test]
+    fn stats_count_what_ants_did() {
+        // Brings the two units of food next to the nest home, one at a time
+        let trip = "  Move end\n  PickUp end\n  Turn Left\n  Turn Left\n  Turn Left\n  Move end\n  Drop\n  Turn Left\n  Turn Left\n  Turn Left\n";
+        let shuttle = format!("{}{}end:\n  Goto end\n", trip, trip);
+        let world = "row\n4\n1\n+ 2 . -\n";
+
+        let mut simulation = Simulation::from_sources(world, &shuttle, IDLE, None, SimulationRules::default()).unwrap();
+        simulation.run_for(200, |_, _| ());
+        assert_eq!(simulation.points(), (2, 0));
+        assert_eq!(simulation.stats().red, Stats { moves: 4, food_picked_up: 2, food_delivered: 2, kills: 0, cooldown_ticks: 4 * 14 });
+        assert_eq!(simulation.stats().black, Stats::default());
+    }
+
+    #[test]
+    fn nests_turn_food_into_ants() {
+        let rules = SimulationRules { spawn_cost: Some(2), ..Default::default() };
+        let mut simulation = Simulation::from_sources(WORLD, IDLE, IDLE, None, rules).unwrap();
+        simulation.enable_recording();
+        simulation.map.drop_food((0, 0), 5);
+
+        simulation.run_for(3, |_, _| ());
+        assert_eq!(ant_counts(&simulation), (3, 1));
+        assert_eq!(simulation.map[(0, 0)].food(), 1);
+        // The nest being taken, new ants are placed around it
+        assert_eq!(simulation.replay()[0].events, vec![Event::Spawned { ant: 2, colour: Colour::Red, cell: (1, 0) }]);
+        assert_eq!(simulation.replay()[1].events, vec![Event::Spawned { ant: 3, colour: Colour::Red, cell: (0, 1) }]);
+        assert!(simulation.replay()[2].events.is_empty());
+    }
+
+    #[test]
+    fn nests_stop_spawning_at_max_ants_per_team() {
+        let rules = SimulationRules { spawn_cost: Some(1), max_ants_per_team: Some(2), ..Default::default() };
+        let mut simulation = Simulation::from_sources(WORLD, IDLE, IDLE, Some(0), rules).unwrap();
+        simulation.map.drop_food((0, 0), 5);
+
+        simulation.process_tick();
+        assert_eq!(ant_counts(&simulation), (2, 1));
+        for _ in 0..4 {
+            simulation.process_tick();
+        }
+        assert_eq!(ant_counts(&simulation), (2, 1));
+        // The food left is kept for when the team has room again
+        assert_eq!(simulation.map[(0, 0)].food(), 4);
+    }
+
+    #[test]
+    fn same_brain_path_is_loaded_once() {
+        let world = fixture("same_brain_path", "open.world", WORLD);
+        let brain = fixture("same_brain_path", "a.brain", BRAIN);
+        let copy = fixture("same_brain_path", "b.brain", BRAIN);
+
+        let shared = Simulation::new(&world, &brain, &brain, None).unwrap();
+        assert!(Rc::ptr_eq(&shared.instructions[0], &shared.instructions[1]));
+        assert!(shared.same_brains());
+
+        let copied = Simulation::new(&world, &brain, &copy, None).unwrap();
+        assert!(!Rc::ptr_eq(&copied.instructions[0], &copied.instructions[1]));
+        assert!(copied.same_brains());
+    }
+
+    #[test]
+    fn depleted_food_grows_back() {
+        let rules = SimulationRules { regrowth: Some(RegrowthConfig { interval: 5, amount: 1 }), ..Default::default() };
+        let mut simulation = Simulation::from_sources(WORLD, IDLE, IDLE, None, rules).unwrap();
+        for _ in 0..3 {
+            assert!(simulation.map.pickup_food((1, 2)));
+        }
+        simulation.map.drop_food((0, 0), 2);
+
+        simulation.run_for(4, |_, _| ());
+        assert_eq!(simulation.map[(1, 2)].food(), 0);
+        simulation.process_tick();
+        assert_eq!(simulation.map[(1, 2)].food(), 1);
+        // Food never grows past what the cell started with, nor anywhere else
+        simulation.run_for(50, |_, _| ());
+        assert_eq!(simulation.map[(1, 2)].food(), 3);
+        assert_eq!(simulation.map.loose_food(), 3);
+        assert_eq!(simulation.points(), (2, 0));
+    }
+
+    // Tick, instruction index, next instruction, position and direction of a traced ant
+    type Step = (usize, usize, usize, (usize, usize), CardinalDirection);
+
+    #[test]
+    fn traced_ants_report_each_instruction() {
+        let mut simulation = Simulation::from_sources(WORLD, BRAIN, BRAIN, Some(0), SimulationRules::default()).unwrap();
+        let steps: Rc<RefCell<Vec<Step>>> = Rc::new(RefCell::new(vec!()));
+        let traced = Rc::clone(&steps);
+        simulation.trace_ant(0, move |step| {
+            traced.borrow_mut().push((step.tick, step.instruction_index, step.next_instruction, step.position, step.direction))
+        });
+        simulation.run_for(19, |_, _| ());
+
+        // Nothing is traced while the ant waits after moving, and moving off the map fails
+        let recorded: &RefCell<Vec<Step>> = steps.borrow();
+        assert_eq!(*recorded.borrow(), vec![
+            (0, 0, 1, (1, 0), CardinalDirection::East),
+            (15, 1, 2, (1, 0), CardinalDirection::NorthEast),
+            (16, 2, 0, (1, 0), CardinalDirection::NorthEast),
+            (17, 0, 1, (1, 0), CardinalDirection::NorthEast),
+            (18, 1, 2, (1, 0), CardinalDirection::NorthWest)
+        ]);
+
+        simulation.stop_tracing();
+        simulation.run_for(20, |_, _| ());
+        assert_eq!(recorded.borrow().len(), 5);
+    }
+}
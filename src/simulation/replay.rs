@@ -0,0 +1,35 @@
+use std::fs::File;
+use std::io::{self, BufWriter};
+use serde::Serialize;
+use super::ant::{CardinalDirection, Colour};
+
+// A change in the simulation's state, mostly caused by an ant
+// Ants are identified by their id, cells by their position
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
+pub enum Event {
+    Moved { ant: usize, from: (usize, usize), to: (usize, usize) },
+    Turned { ant: usize, direction: CardinalDirection },
+    PickedUp { ant: usize, cell: (usize, usize) },
+    Dropped { ant: usize, cell: (usize, usize), amount: u32 },
+    Marked { colour: Colour, cell: (usize, usize), marker: usize },
+    Unmarked { colour: Colour, cell: (usize, usize), marker: usize },
+    Killed { ant: usize, cell: (usize, usize) },
+    // The ant reached the end of its lifespan
+    Died { ant: usize, cell: (usize, usize) },
+    // A nest turned some of its food into a new ant
+    Spawned { ant: usize, colour: Colour, cell: (usize, usize) }
+}
+
+// Everything that changed during one tick
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TickFrame {
+    pub tick: usize,
+    pub events: Vec<Event>
+}
+
+// Writes a recorded replay to disk as JSON
+pub fn save_replay(frames: &[TickFrame], path: &str) -> io::Result<()> {
+    let writer = BufWriter::new(File::create(path)?);
+    serde_json::to_writer(writer, frames)?;
+    Ok(())
+}
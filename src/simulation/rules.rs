@@ -0,0 +1,44 @@
+// Food growing back on the cells which started with some
+#[derive(Debug, Copy, Clone)]
+pub struct RegrowthConfig {
+    // Number of ticks between two regrowths
+    pub interval: usize,
+    // Units of food added to each food source at once
+    pub amount: u32
+}
+
+// Tunable rules of a game, defaulting to the standard ones
+#[derive(Debug, Copy, Clone)]
+pub struct SimulationRules {
+    // Units of food an ant can carry at once
+    pub food_capacity: u32,
+    // Number of ticks an ant has to wait after a successful move
+    pub move_cooldown: u8,
+    // Food does not grow back when None
+    pub regrowth: Option<RegrowthConfig>,
+    // A game is over once no food has been on the ground or carried by an ant
+    // for this many consecutive ticks
+    pub stalemate_ticks: usize,
+    // Most ants each colour can have at once: only that many nest cells of each
+    // colour get an ant when the game starts, and nests stop spawning ants while
+    // the team has that many living ants, there being no limit when None
+    pub max_ants_per_team: Option<usize>,
+    // Number of ticks an ant lives for, ants never dying of old age when None
+    pub ant_lifespan: Option<u32>,
+    // Units of food a nest cell turns into a new ant, nests never spawning
+    // ants when None or 0
+    pub spawn_cost: Option<u32>
+}
+impl Default for SimulationRules {
+    fn default() -> Self {
+        Self {
+            food_capacity: 1,
+            move_cooldown: 14,
+            regrowth: None,
+            stalemate_ticks: 1000,
+            max_ants_per_team: None,
+            ant_lifespan: None,
+            spawn_cost: None
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use std::cmp::Ordering;
+use serde::{Serialize, Serializer};
+use serde::ser::SerializeStruct;
+use super::ant::Colour;
+
+// Counters describing what a team's ants did during a game
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Serialize)]
+pub struct Stats {
+    pub moves: u32,
+    pub food_picked_up: u32,
+    // Food dropped on one of the team's own nest cells
+    pub food_delivered: u32,
+    pub kills: u32,
+    // Ticks spent by the team's ants waiting after a move
+    pub cooldown_ticks: u32
+}
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Serialize)]
+pub struct TeamStats {
+    pub red: Stats,
+    pub black: Stats
+}
+impl TeamStats {
+    pub fn get(&self, colour: Colour) -> &Stats {
+        match colour {
+            Colour::Red => &self.red,
+            Colour::Black => &self.black
+        }
+    }
+    pub fn get_mut(&mut self, colour: Colour) -> &mut Stats {
+        match colour {
+            Colour::Red => &mut self.red,
+            Colour::Black => &mut self.black
+        }
+    }
+}
+
+// Who is ahead in a game, given each team's (red, black) points and surviving ants
+// Ties on food are broken by the number of surviving ants
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum GameOutcome {
+    RedWins { points: (u32, u32), ants: (usize, usize) },
+    BlackWins { points: (u32, u32), ants: (usize, usize) },
+    Draw { points: (u32, u32), ants: (usize, usize) }
+}
+// Serialized as flat fields for scripts: red_points, black_points, the winner ("red",
+// "black" or "draw"), red_ants and black_ants
+impl Serialize for GameOutcome {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let ((red_points, black_points), (red_ants, black_ants)) = (self.points(), self.ants());
+        let winner = match self {
+            Self::RedWins { .. } => "red",
+            Self::BlackWins { .. } => "black",
+            Self::Draw { .. } => "draw"
+        };
+        let mut outcome = serializer.serialize_struct("GameOutcome", 5)?;
+        outcome.serialize_field("red_points", &red_points)?;
+        outcome.serialize_field("black_points", &black_points)?;
+        outcome.serialize_field("winner", winner)?;
+        outcome.serialize_field("red_ants", &red_ants)?;
+        outcome.serialize_field("black_ants", &black_ants)?;
+        outcome.end()
+    }
+}
+impl GameOutcome {
+    pub fn new(points: (u32, u32), ants: (usize, usize)) -> Self {
+        match points.0.cmp(&points.1).then(ants.0.cmp(&ants.1)) {
+            Ordering::Greater => Self::RedWins { points, ants },
+            Ordering::Less => Self::BlackWins { points, ants },
+            Ordering::Equal => Self::Draw { points, ants }
+        }
+    }
+
+    pub fn points(&self) -> (u32, u32) {
+        match *self {
+            Self::RedWins { points, .. } | Self::BlackWins { points, .. } | Self::Draw { points, .. } => points
+        }
+    }
+
+    pub fn ants(&self) -> (usize, usize) {
+        match *self {
+            Self::RedWins { ants, .. } | Self::BlackWins { ants, .. } | Self::Draw { ants, .. } => ants
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn surviving_ants_break_ties_on_food() {
+        assert_eq!(GameOutcome::new((5, 2), (1, 4)), GameOutcome::RedWins { points: (5, 2), ants: (1, 4) });
+        assert_eq!(GameOutcome::new((3, 3), (2, 4)), GameOutcome::BlackWins { points: (3, 3), ants: (2, 4) });
+        let draw = GameOutcome::new((3, 3), (2, 2));
+        assert_eq!(draw, GameOutcome::Draw { points: (3, 3), ants: (2, 2) });
+        assert_eq!((draw.points(), draw.ants()), ((3, 3), (2, 2)));
+    }
+}
@@ -0,0 +1,61 @@
+use std::process::{Command, Output};
+
+// Path of one of the worlds or brains under tests/fixtures, shared with the library's tests
+fn fixture(name: &str) -> String {
+    format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+fn fourmisse_arena(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_fourmisse-arena"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn zero_games_are_refused() {
+    let (world, brain) = (fixture("open.world"), fixture("idle.brain"));
+
+    let output = fourmisse_arena(&["-w", &world, "-r", &brain, "-b", &brain, "-g", "0"]);
+    assert_eq!(output.status.code(), Some(2));
+    let output = fourmisse_arena(&["tournament", "-w", &world, "-b", &fixture(""), "-g", "0"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn check_fails_on_broken_brains() {
+    let output = fourmisse_arena(&["check", &fixture("wanderer.brain")]);
+    assert_eq!(output.status.code(), Some(0));
+
+    let output = fourmisse_arena(&["check", &fixture("broken.brain")]);
+    assert_ne!(output.status.code(), Some(0));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("error: line 5"), "{}", stdout);
+    assert!(stdout.contains("warning: lines 4-5: unreachable instructions"), "{}", stdout);
+
+    // An empty brain would leave ants nothing to run
+    let output = fourmisse_arena(&["check", &fixture("empty.brain")]);
+    assert_ne!(output.status.code(), Some(0));
+}
+
+#[test]
+fn each_brain_plays_its_own_colour() {
+    let (world, forager, idle) = (fixture("tiny.world"), fixture("forager.brain"), fixture("idle.brain"));
+    let winner = |red: &str, black: &str| {
+        let output = fourmisse_arena(&["-w", &world, "-r", red, "-b", black, "-t", "3000", "-s", "7", "-f", "json"]);
+        assert!(output.status.success());
+        let result: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+        result["winner"].clone()
+    };
+    assert_eq!(winner(&forager, &idle), "red");
+    assert_eq!(winner(&idle, &forager), "black");
+}
+
+#[test]
+fn obstacle_density_must_be_a_probability() {
+    for density in ["nan", "inf", "-0.5", "1.5"] {
+        let output = fourmisse_arena(&["generate", "--obstacle-density", density]);
+        assert_eq!(output.status.code(), Some(2), "{}", density);
+    }
+    assert!(fourmisse_arena(&["generate", "--obstacle-density", "1"]).status.success());
+}